@@ -0,0 +1,194 @@
+use std::{collections::HashMap, time::Duration};
+
+use axum::{
+    extract::{FromRequestParts, Json, State},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+};
+use axum_extra::routing::TypedPath;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationMilliSeconds, FromInto};
+use shakmaty::variant::Variant;
+
+use crate::{
+    api::Priority,
+    engines::user_token,
+    hub::Hub,
+    job::{Job, Running},
+    model::{secret_eq, EngineId, JobId, ProviderSelector, SessionId, UciVariant, UserId},
+    ongoing::Ongoing,
+    AppState, Error, Opt,
+};
+
+/// Requires `Authorization: Bearer <admin secret>`. Without a configured
+/// admin secret, all admin requests are rejected.
+pub struct Admin;
+
+impl FromRequestParts<AppState> for Admin {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Admin, Error> {
+        let secret = state
+            .opt
+            .admin_secret
+            .as_deref()
+            .ok_or(Error::Unauthorized)?;
+        let bearer = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if bearer.is_some_and(|bearer| secret_eq(bearer, secret)) {
+            Ok(Admin)
+        } else {
+            Err(Error::Unauthorized)
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct QueuedJob {
+    id: JobId,
+    engine: EngineId,
+    #[serde_as(as = "FromInto<UciVariant>")]
+    variant: Variant,
+    session_id: SessionId,
+    priority: Priority,
+    #[serde_as(as = "DurationMilliSeconds")]
+    age: Duration,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct InFlightJob {
+    id: JobId,
+    #[serde_as(as = "DurationMilliSeconds")]
+    age: Duration,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ProviderQueue {
+    queued: Vec<QueuedJob>,
+    in_flight: Vec<InFlightJob>,
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/admin/queues")]
+pub struct QueuesPath;
+
+/// Lists queued and in-flight jobs by provider selector.
+#[axum_macros::debug_handler(state = AppState)]
+pub async fn queues(
+    _: QueuesPath,
+    _: Admin,
+    State(hub): State<&'static Hub<ProviderSelector, Job>>,
+    State(running): State<&'static Ongoing<JobId, Running>>,
+) -> Json<HashMap<ProviderSelector, ProviderQueue>> {
+    let mut queues: HashMap<ProviderSelector, ProviderQueue> = HashMap::new();
+    hub.for_each(|selector, job| {
+        queues
+            .entry(selector.clone())
+            .or_default()
+            .queued
+            .push(QueuedJob {
+                id: job.id.clone(),
+                engine: job.engine.id.clone(),
+                variant: job.work.variant(),
+                session_id: job.work.session_id().clone(),
+                priority: job.work.priority(),
+                age: job.queued_at.elapsed(),
+            });
+    });
+    running.for_each(|id, running| {
+        queues
+            .entry(running.selector.clone())
+            .or_default()
+            .in_flight
+            .push(InFlightJob {
+                id: id.clone(),
+                age: running.acquired_at.elapsed(),
+            });
+    });
+    Json(queues)
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/admin/queues/{selector}")]
+pub struct QueuePath {
+    selector: ProviderSelector,
+}
+
+/// Cancels all queued jobs of a provider.
+#[axum_macros::debug_handler(state = AppState)]
+pub async fn flush(
+    QueuePath { selector }: QueuePath,
+    _: Admin,
+    State(hub): State<&'static Hub<ProviderSelector, Job>>,
+) -> StatusCode {
+    for job in hub.flush(&selector) {
+        job.cancel.cancel();
+    }
+    StatusCode::NO_CONTENT
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/admin/work/{id}")]
+pub struct EvictPath {
+    id: JobId,
+}
+
+/// Cancels a queued or in-flight job.
+#[axum_macros::debug_handler(state = AppState)]
+pub async fn evict(
+    EvictPath { id }: EvictPath,
+    _: Admin,
+    State(hub): State<&'static Hub<ProviderSelector, Job>>,
+    State(running): State<&'static Ongoing<JobId, Running>>,
+) -> Result<StatusCode, Error> {
+    if let Some(cancel) = running
+        .get(&id)
+        .and_then(|running| running.cancel.upgrade())
+    {
+        cancel.cancel();
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    let mut found = false;
+    hub.for_each(|_, job| {
+        if job.id == id {
+            job.cancel.cancel();
+            found = true;
+        }
+    });
+    if found {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(Error::WorkNotFound)
+    }
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/admin/users/{user_id}/token")]
+pub struct UserTokenPath {
+    user_id: UserId,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UserToken {
+    token: String,
+}
+
+/// Issues a token for the engine management API of a user.
+#[axum_macros::debug_handler(state = AppState)]
+pub async fn issue_user_token(
+    UserTokenPath { user_id }: UserTokenPath,
+    _: Admin,
+    State(opt): State<&'static Opt>,
+) -> Result<Json<UserToken>, Error> {
+    let secret = opt.user_secret.as_deref().ok_or(Error::Unauthorized)?;
+    Ok(Json(UserToken {
+        token: user_token(secret, &user_id),
+    }))
+}
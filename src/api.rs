@@ -1,4 +1,7 @@
-use std::{cmp::min, num::NonZeroU32};
+use std::{
+    cmp::min,
+    num::{NonZeroU32, NonZeroUsize},
+};
 
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr, FromInto, TryFromInto};
@@ -10,7 +13,9 @@ use shakmaty::{
 };
 use thiserror::Error;
 
-use crate::model::{ClientSecret, Engine, JobId, MultiPv, ProviderSecret, SessionId, UciVariant};
+use crate::model::{
+    ClientSecret, Engine, JobId, MultiPv, ProviderSecret, SessionId, UciVariant, WorkerId,
+};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -20,11 +25,23 @@ pub enum Search {
     Nodes(u64),
 }
 
+/// Scheduling class of work. Higher classes are acquired first.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Priority {
+    Batch = 0,
+    Background = 1,
+    #[default]
+    Interactive = 2,
+}
+
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Work {
     session_id: SessionId,
+    #[serde(default)]
+    priority: Priority,
     threads: NonZeroU32,
     hash: NonZeroU32,
     #[serde(flatten)]
@@ -58,6 +75,22 @@ impl From<PositionError<VariantPosition>> for InvalidWorkError {
 }
 
 impl Work {
+    pub fn session_id(&self) -> &SessionId {
+        &self.session_id
+    }
+
+    pub fn search(&self) -> &Search {
+        &self.search
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
     pub fn sanitize(self, engine: &Engine) -> Result<(Work, VariantPosition), InvalidWorkError> {
         if !engine
             .config
@@ -89,6 +122,7 @@ impl Work {
         Ok((
             Work {
                 session_id: self.session_id,
+                priority: self.priority,
                 threads: min(self.threads, engine.config.max_threads),
                 hash: min(self.hash, engine.config.max_hash),
                 search: self.search,
@@ -109,10 +143,43 @@ pub struct AnalyseRequest {
     pub work: Work,
 }
 
+/// Engine as registered by its owner. The client secret is generated by the
+/// server.
+#[serde_as]
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineRegistration {
+    pub name: String,
+    pub max_threads: NonZeroU32,
+    pub max_hash: NonZeroU32,
+    #[serde_as(as = "Vec<FromInto<UciVariant>>")]
+    pub variants: Vec<Variant>,
+    pub provider_data: Option<String>,
+    pub provider_secret: ProviderSecret,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SocketAuth {
+    pub client_secret: ClientSecret,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelRequest {
+    pub client_secret: ClientSecret,
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct AcquireRequest {
     pub provider_secret: ProviderSecret,
+    /// Lets successive work of a session go to the same engine process.
+    #[serde(default)]
+    pub worker_id: Option<WorkerId>,
+    /// Acquires up to this many jobs at once, responding with a list.
+    #[serde(default)]
+    pub max_jobs: Option<NonZeroUsize>,
 }
 
 #[derive(Serialize, Debug)]
@@ -122,3 +189,17 @@ pub struct AcquireResponse {
     pub work: Work,
     pub engine: Engine,
 }
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+#[allow(clippy::large_enum_variant)]
+pub enum ProviderSocketOut {
+    Work(AcquireResponse),
+    Cancel { id: JobId },
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ProviderSocketIn {
+    Uci { id: JobId, line: String },
+}
@@ -1,12 +1,16 @@
-use std::{cmp::min, time::Duration};
+use std::{
+    cmp::{max, min},
+    time::Duration,
+};
 
 use serde::Serialize;
 use serde_with::{serde_as, DisplayFromStr, DurationMilliSeconds};
 use shakmaty::{uci::UciMove, variant::VariantPosition, CastlingMode, Position};
 
 use crate::{
+    api::Search,
     model::MultiPv,
-    uci::{Eval, UciOut},
+    uci::{Eval, ProtocolError, UciOut},
 };
 
 #[serde_as]
@@ -118,3 +122,189 @@ impl Emit {
         !self.pvs.is_empty() && self.pvs.iter().all(|pv| pv.is_some())
     }
 }
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Completion {
+    /// The search limit of the work was reached. For `movetime`, this is
+    /// judged by the time since the provider acquired the work, because
+    /// engines tend to report slightly less time than requested.
+    Limit,
+    /// The engine stopped before reaching the search limit.
+    Early,
+    /// There are no legal moves in the position.
+    NoMove,
+}
+
+/// Final result of a search.
+#[serde_as]
+#[derive(Clone, Debug, Serialize)]
+pub struct Bestmove {
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    bestmove: Option<UciMove>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    ponder: Option<UciMove>,
+    #[serde_as(as = "DurationMilliSeconds")]
+    time: Duration,
+    depth: u32,
+    nodes: u64,
+    reason: Completion,
+}
+
+impl Bestmove {
+    /// Extracts the result of the search from a `bestmove` line, given the
+    /// time since the work was acquired. Fails if the best move is illegal.
+    /// An illegal ponder move is dropped.
+    pub fn extract(
+        uci: &UciOut,
+        emit: &Emit,
+        search: &Search,
+        pos: &VariantPosition,
+        elapsed: Duration,
+    ) -> Result<Option<Bestmove>, ProtocolError> {
+        let UciOut::Bestmove { m, ponder } = *uci else {
+            return Ok(None);
+        };
+        let (bestmove, ponder) = match m {
+            Some(m) => {
+                let mut moves = normalize_pv(&[m], pos.clone());
+                let bestmove = moves.pop().ok_or(ProtocolError::IllegalMove(m))?;
+                let ponder = ponder
+                    .and_then(|ponder| normalize_pv(&[m, ponder], pos.clone()).into_iter().nth(1));
+                (Some(bestmove), ponder)
+            }
+            None => (None, None),
+        };
+        Ok(Some(Bestmove {
+            bestmove,
+            ponder,
+            time: emit.time,
+            depth: emit.depth,
+            nodes: emit.nodes,
+            reason: if bestmove.is_none() {
+                Completion::NoMove
+            } else if match *search {
+                Search::Movetime(ms) => max(emit.time, elapsed) >= Duration::from_millis(ms.into()),
+                Search::Depth(depth) => emit.depth >= depth,
+                Search::Nodes(nodes) => emit.nodes >= nodes,
+            } {
+                Completion::Limit
+            } else {
+                Completion::Early
+            },
+        }))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorKind {
+    InvalidWork,
+    /// The engine does not exist, or the client secret is wrong.
+    EngineNotFound,
+    /// No provider picked up the work in time.
+    Timeout,
+    /// Too much work is already queued for the provider. Retry later.
+    ProviderOverloaded,
+    Cancelled,
+    /// The provider sent malformed engine output.
+    Protocol,
+    /// The provider went away before sending `bestmove`.
+    ProviderDisconnected,
+    /// The server is shutting down. Retry later.
+    Restarting,
+    /// Too many requests. Retry later.
+    RateLimited,
+    Internal,
+}
+
+/// Everything that is sent to the requester of a job. Analysis output is
+/// untagged, all other lines carry an `event` field.
+#[serde_as]
+#[derive(Clone, Debug, Serialize)]
+#[serde(
+    tag = "event",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Event {
+    Queued {
+        position: usize,
+        #[serde_as(as = "Option<DurationMilliSeconds>")]
+        estimated_wait: Option<Duration>,
+    },
+    Started,
+    Bestmove(Bestmove),
+    Error {
+        error: ErrorKind,
+        message: String,
+    },
+    #[serde(untagged)]
+    Info(Emit),
+}
+
+impl Event {
+    /// Event type, or `None` for regular analysis output.
+    pub fn name(&self) -> Option<&'static str> {
+        match self {
+            Event::Queued { .. } => Some("queued"),
+            Event::Started => Some("started"),
+            Event::Bestmove(_) => Some("bestmove"),
+            Event::Error { .. } => Some("error"),
+            Event::Info(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::Chess;
+
+    use super::*;
+
+    fn extract(lines: &[&str], search: Search, elapsed: u64) -> Result<Bestmove, ProtocolError> {
+        let pos = VariantPosition::Chess(Chess::default());
+        let mut emit = Emit::default();
+        let (bestmove, info) = lines.split_last().unwrap();
+        for line in info {
+            emit.update(&UciOut::from_line(line)?.unwrap(), &pos);
+        }
+        let uci = UciOut::from_line(bestmove)?.unwrap();
+        let elapsed = Duration::from_millis(elapsed);
+        Ok(Bestmove::extract(&uci, &emit, &search, &pos, elapsed)?.unwrap())
+    }
+
+    #[test]
+    fn test_bestmove() {
+        let info = "info depth 12 time 997 nodes 1000 score cp 20 pv e2e4 e7e5";
+        let limit = extract(
+            &[info, "bestmove e2e4 ponder e7e5"],
+            Search::Movetime(1000),
+            1005,
+        )
+        .unwrap();
+        assert_eq!(limit.bestmove, Some("e2e4".parse().unwrap()));
+        assert_eq!(limit.ponder, Some("e7e5".parse().unwrap()));
+        assert!(matches!(limit.reason, Completion::Limit));
+
+        let early = extract(&[info, "bestmove e2e4"], Search::Movetime(1000), 998).unwrap();
+        assert!(matches!(early.reason, Completion::Early));
+        let early = extract(&[info, "bestmove e2e4"], Search::Depth(20), 5000).unwrap();
+        assert!(matches!(early.reason, Completion::Early));
+        let limit = extract(&[info, "bestmove e2e4"], Search::Nodes(1000), 0).unwrap();
+        assert!(matches!(limit.reason, Completion::Limit));
+
+        let illegal_ponder = extract(&["bestmove e2e4 ponder e2e4"], Search::Depth(1), 0).unwrap();
+        assert_eq!(illegal_ponder.bestmove, Some("e2e4".parse().unwrap()));
+        assert_eq!(illegal_ponder.ponder, None);
+
+        let no_move = extract(&["bestmove (none)"], Search::Depth(1), 0).unwrap();
+        assert_eq!(no_move.bestmove, None);
+        assert!(matches!(no_move.reason, Completion::NoMove));
+
+        assert!(matches!(
+            extract(&["bestmove a1a8"], Search::Depth(1), 0),
+            Err(ProtocolError::IllegalMove(_))
+        ));
+    }
+}
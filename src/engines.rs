@@ -0,0 +1,199 @@
+use axum::{
+    extract::{FromRequestParts, Json, State},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+};
+use axum_extra::routing::TypedPath;
+use hmac::{Hmac, KeyInit, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::{
+    api::EngineRegistration,
+    model::{ClientSecret, Engine, EngineConfig, EngineId, UserId},
+    repo::{ExternalEngine, Repo},
+    AppState, Error,
+};
+
+fn user_mac(secret: &str, user_id: &UserId) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac key");
+    mac.update(b"user:");
+    mac.update(user_id.0.as_bytes());
+    mac
+}
+
+/// Creates a token that authenticates requests of a user to manage their
+/// engines.
+pub fn user_token(secret: &str, user_id: &UserId) -> String {
+    let signature = user_mac(secret, user_id).finalize().into_bytes();
+    format!("{user_id}:{}", hex::encode(signature))
+}
+
+/// Requires `Authorization: Bearer <user token>`. Without a configured user
+/// secret, all requests are rejected.
+pub struct User(pub UserId);
+
+impl FromRequestParts<AppState> for User {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<User, Error> {
+        let secret = state
+            .opt
+            .user_secret
+            .as_deref()
+            .ok_or(Error::Unauthorized)?;
+        let (user_id, signature) = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| token.rsplit_once(':'))
+            .ok_or(Error::Unauthorized)?;
+        let user_id = UserId(user_id.to_owned());
+        let signature = hex::decode(signature).map_err(|_| Error::Unauthorized)?;
+        user_mac(secret, &user_id)
+            .verify_slice(&signature)
+            .map_err(|_| Error::Unauthorized)?;
+        Ok(User(user_id))
+    }
+}
+
+fn validate(registration: &EngineRegistration) -> Result<(), Error> {
+    if registration.name.is_empty() || registration.name.chars().count() > 200 {
+        return Err(Error::InvalidRegistration(
+            "name must have 1 to 200 characters",
+        ));
+    }
+    if registration.variants.is_empty() {
+        return Err(Error::InvalidRegistration("at least one variant required"));
+    }
+    Ok(())
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine")]
+pub struct EnginesPath;
+
+#[axum_macros::debug_handler(state = AppState)]
+pub async fn list(
+    _: EnginesPath,
+    User(user_id): User,
+    State(repo): State<&'static Repo>,
+) -> Result<Json<Vec<Engine>>, Error> {
+    Ok(Json(
+        repo.list(user_id)
+            .await?
+            .into_iter()
+            .map(|engine| engine.into_engine_and_selector().0)
+            .collect(),
+    ))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+pub async fn create(
+    _: EnginesPath,
+    User(user_id): User,
+    State(repo): State<&'static Repo>,
+    Json(registration): Json<EngineRegistration>,
+) -> Result<(StatusCode, Json<Engine>), Error> {
+    validate(&registration)?;
+    let engine = ExternalEngine {
+        id: EngineId::random(),
+        provider_selector: registration.provider_secret.selector(),
+        config: EngineConfig {
+            name: registration.name,
+            client_secret: ClientSecret::random(),
+            user_id,
+            max_threads: registration.max_threads,
+            max_hash: registration.max_hash,
+            variants: registration.variants,
+            provider_data: registration.provider_data,
+            pickup_timeout: None,
+            max_concurrent_jobs: None,
+        },
+    };
+    repo.insert(engine.clone()).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(engine.into_engine_and_selector().0),
+    ))
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/{id}")]
+pub struct EnginePath {
+    id: EngineId,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+pub async fn get(
+    EnginePath { id }: EnginePath,
+    User(user_id): User,
+    State(repo): State<&'static Repo>,
+) -> Result<Json<Engine>, Error> {
+    let engine = repo
+        .find_owned(id, &user_id)
+        .await?
+        .ok_or(Error::EngineNotFound)?;
+    Ok(Json(engine.into_engine_and_selector().0))
+}
+
+/// Replaces the registration. A new provider secret rotates the provider
+/// selector.
+#[axum_macros::debug_handler(state = AppState)]
+pub async fn update(
+    EnginePath { id }: EnginePath,
+    User(user_id): User,
+    State(repo): State<&'static Repo>,
+    Json(registration): Json<EngineRegistration>,
+) -> Result<Json<Engine>, Error> {
+    validate(&registration)?;
+    let mut engine = repo
+        .find_owned(id, &user_id)
+        .await?
+        .ok_or(Error::EngineNotFound)?;
+    engine.provider_selector = registration.provider_secret.selector();
+    engine.config.name = registration.name;
+    engine.config.max_threads = registration.max_threads;
+    engine.config.max_hash = registration.max_hash;
+    engine.config.variants = registration.variants;
+    engine.config.provider_data = registration.provider_data;
+    repo.update(engine.clone()).await?;
+    Ok(Json(engine.into_engine_and_selector().0))
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+pub async fn delete(
+    EnginePath { id }: EnginePath,
+    User(user_id): User,
+    State(repo): State<&'static Repo>,
+) -> Result<StatusCode, Error> {
+    let engine = repo
+        .find_owned(id, &user_id)
+        .await?
+        .ok_or(Error::EngineNotFound)?;
+    repo.delete(engine.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/{id}/client-secret")]
+pub struct ClientSecretPath {
+    id: EngineId,
+}
+
+/// Generates a new client secret. Requests with the old secret are
+/// rejected from now on.
+#[axum_macros::debug_handler(state = AppState)]
+pub async fn rotate_client_secret(
+    ClientSecretPath { id }: ClientSecretPath,
+    User(user_id): User,
+    State(repo): State<&'static Repo>,
+) -> Result<Json<Engine>, Error> {
+    let mut engine = repo
+        .find_owned(id, &user_id)
+        .await?
+        .ok_or(Error::EngineNotFound)?;
+    engine.config.client_secret = ClientSecret::random();
+    repo.update(engine.clone()).await?;
+    Ok(Json(engine.into_engine_and_selector().0))
+}
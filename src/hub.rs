@@ -2,117 +2,716 @@ use std::{
     array,
     collections::{hash_map::RandomState, HashMap, VecDeque},
     hash::{BuildHasher, Hash},
-    sync::{Arc, Mutex},
-    time::Duration,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
-use tokio::{sync::Notify, time::sleep};
+use tokio::{
+    select,
+    sync::Notify,
+    time::{sleep, sleep_until},
+};
 
 const NUM_SHARDS: usize = 64;
 
-const MAX_ITEMS: usize = 1024;
-
 pub trait IsValid {
     fn is_valid(&self) -> bool;
 }
 
-pub struct Hub<S, R> {
+/// Items that should preferably be acquired by a specific worker.
+pub trait Affinity {
+    type Worker: Eq;
+
+    /// The preferred worker, and until when other workers must not acquire
+    /// the item.
+    fn affinity(&self) -> Option<(&Self::Worker, Instant)>;
+}
+
+/// Items with higher priority are acquired first, and otherwise in queue
+/// order.
+pub trait Prioritized {
+    /// Highest priority class. Waiting items are not promoted beyond it.
+    const MAX_PRIORITY: u32;
+
+    fn priority(&self) -> u32;
+
+    fn queued_at(&self) -> Instant;
+}
+
+/// Items are shared fairly between owners, by serving the owner that was
+/// served least recently first. Owners can be limited in how many items they
+/// have in flight at once.
+pub trait Owned {
+    type Owner: Hash + Eq + Clone;
+
+    fn owner(&self) -> &Self::Owner;
+
+    fn max_in_flight(&self) -> Option<usize>;
+
+    /// Keeps the acquired item counted as in flight until the guard is
+    /// dropped.
+    fn set_in_flight(&mut self, in_flight: InFlight);
+}
+
+/// Counts an acquired item as in flight for its owner.
+pub struct InFlight {
+    count: Arc<AtomicUsize>,
+    signal: Arc<Notify>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+        // Items of the owner may have been waiting for capacity.
+        self.signal.notify_one();
+    }
+}
+
+pub struct Hub<S, R: Owned> {
     random_state: RandomState,
+    max_items: usize,
+    /// Waiting items gain one level of priority per interval, so that items
+    /// with low priority are not starved.
+    aging: Duration,
     shards: [Mutex<Shard<S, R>>; NUM_SHARDS],
 }
 
-impl<S: Hash + Eq, R: IsValid> Default for Hub<S, R> {
-    fn default() -> Hub<S, R> {
+impl<S: Hash + Eq, R: IsValid + Owned> Hub<S, R> {
+    pub fn new(max_items: usize, aging: Duration) -> Hub<S, R> {
         Hub {
             random_state: RandomState::new(),
+            max_items,
+            aging,
             shards: array::from_fn(|_| Mutex::new(Shard::new())),
         }
     }
 }
 
-impl<S: Hash + Eq + Clone, R: IsValid> Hub<S, R> {
-    pub fn submit(&self, selector: S, data: R) {
+impl<S: Hash + Eq + Clone, R: IsValid + Affinity + Prioritized + Owned> Hub<S, R> {
+    /// Queues `data` in place of the first queued item that it supersedes,
+    /// or else at the back. Returns the superseded items, which are removed
+    /// from the queue.
+    pub fn submit<P>(&self, selector: S, data: R, supersedes: P) -> Result<Vec<R>, QueueFull>
+    where
+        P: FnMut(&R) -> bool,
+    {
         let shard = self.shard(&selector);
-        shard.lock().unwrap().submit(selector, data);
+        shard
+            .lock()
+            .unwrap()
+            .submit(selector, data, supersedes, self.max_items)
     }
 
-    pub async fn acquire(&self, selector: S) -> R {
+    /// Acquires the first item with the highest priority that is not
+    /// reserved for another worker.
+    pub async fn acquire(&self, selector: S, worker: Option<&R::Worker>) -> R {
         let shard = self.shard(&selector);
         loop {
-            let res = shard.lock().unwrap().acquire(selector.clone());
+            let res = shard
+                .lock()
+                .unwrap()
+                .acquire(selector.clone(), worker, self.aging);
             match res {
                 Ok(item) => return item,
-                Err(signal) => signal.notified().await,
+                Err((signal, None)) => signal.notified().await,
+                Err((signal, Some(reserved_until))) => select! {
+                    _ = signal.notified() => (),
+                    _ = sleep_until(reserved_until.into()) => (),
+                },
             }
         }
     }
 
+    /// Acquires an item like `acquire`, but without waiting.
+    pub fn try_acquire(&self, selector: S, worker: Option<&R::Worker>) -> Option<R> {
+        let shard = self.shard(&selector);
+        shard
+            .lock()
+            .unwrap()
+            .acquire(selector, worker, self.aging)
+            .ok()
+    }
+
+    /// Finds the first matching item in the queue of `selector`.
+    pub fn status<P>(&self, selector: &S, pred: P) -> Option<QueueStatus>
+    where
+        P: FnMut(&R) -> bool,
+    {
+        self.shard(selector)
+            .lock()
+            .unwrap()
+            .status(selector, pred, self.aging)
+    }
+}
+
+impl<S: Hash + Eq + Clone, R: IsValid + Owned> Hub<S, R> {
+    /// Puts an item that was acquired but not completed back at the front of
+    /// the queue of `selector`, even if the queue is full.
+    pub fn requeue(&self, selector: S, data: R) {
+        let mut shard = self.shard(&selector).lock().unwrap();
+        let entry = shard.map.entry(selector).or_default();
+        entry.inner.push_front(data);
+        entry.signal.notify_one();
+    }
+
+    /// Removes all items from the queue of `selector`.
+    pub fn flush(&self, selector: &S) -> Vec<R> {
+        let mut shard = self.shard(selector).lock().unwrap();
+        shard
+            .map
+            .get_mut(selector)
+            .map(|queue| queue.inner.drain(..).collect())
+            .unwrap_or_default()
+    }
+
     fn shard(&self, selector: &S) -> &Mutex<Shard<S, R>> {
         &self.shards[self.random_state.hash_one(selector) as usize % NUM_SHARDS]
     }
 }
 
-impl<S, R: IsValid> Hub<S, R> {
-    pub async fn garbage_collect(&self) {
+impl<S, R: IsValid + Owned> Hub<S, R> {
+    pub async fn garbage_collect(&self, interval: Duration) {
         loop {
             for shard in &self.shards {
                 shard.lock().unwrap().garbage_collect();
-                sleep(Duration::from_secs(13)).await;
+                sleep(interval).await;
+            }
+        }
+    }
+
+    /// Number of queued items in each shard, including items that are not
+    /// yet garbage collected.
+    pub fn queued(&self) -> impl Iterator<Item = usize> + '_ {
+        self.shards.iter().map(|shard| shard.lock().unwrap().len())
+    }
+
+    /// Visits all valid items, in queue order for each selector.
+    pub fn for_each(&self, mut f: impl FnMut(&S, &R)) {
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            for (selector, queue) in &shard.map {
+                for item in queue.inner.iter().filter(|item| item.is_valid()) {
+                    f(selector, item);
+                }
             }
         }
     }
 }
 
-struct Shard<S, R> {
+struct Shard<S, R: Owned> {
     map: HashMap<S, Queue<R>>,
 }
 
-impl<S: Eq + Hash, R: IsValid> Shard<S, R> {
+impl<S: Eq + Hash, R: IsValid + Owned> Shard<S, R> {
     fn new() -> Shard<S, R> {
         Shard {
             map: HashMap::new(),
         }
     }
 
-    fn submit(&mut self, selector: S, data: R) {
+    fn submit<P>(
+        &mut self,
+        selector: S,
+        data: R,
+        mut supersedes: P,
+        max_items: usize,
+    ) -> Result<Vec<R>, QueueFull>
+    where
+        P: FnMut(&R) -> bool,
+        R: Affinity,
+    {
         let entry = self.map.entry(selector).or_default();
-        if entry.inner.len() < MAX_ITEMS {
+        let mut superseded = Vec::new();
+        let mut position = None;
+        let mut i = 0;
+        while i < entry.inner.len() {
+            if supersedes(&entry.inner[i]) {
+                position.get_or_insert(i);
+                superseded.extend(entry.inner.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        let reserved = data.affinity().is_some();
+        if let Some(position) = position {
+            entry.inner.insert(position, data);
+        } else {
+            if entry.inner.len() >= max_items {
+                entry.inner.retain(|item| item.is_valid());
+                if entry.inner.len() >= max_items {
+                    return Err(QueueFull);
+                }
+            }
             entry.inner.push_back(data);
-            entry.signal.notify_one();
         }
+        if reserved {
+            // Wake all workers, in case the preferred worker is not next.
+            entry.signal.notify_waiters();
+        }
+        entry.signal.notify_one();
+        Ok(superseded)
     }
 
-    fn acquire(&mut self, selector: S) -> Result<R, Arc<Notify>> {
+    /// Fails with the signal for new items, and the time when the next item
+    /// reserved for another worker becomes available, if any.
+    fn acquire(
+        &mut self,
+        selector: S,
+        worker: Option<&R::Worker>,
+        aging: Duration,
+    ) -> Result<R, (Arc<Notify>, Option<Instant>)>
+    where
+        R: Affinity + Prioritized,
+    {
         let entry = self.map.entry(selector).or_default();
-        loop {
-            match entry.inner.pop_front() {
-                Some(item) if item.is_valid() => return Ok(item),
-                Some(_) => continue,
-                None => return Err(Arc::clone(&entry.signal)),
+        while entry.inner.front().is_some_and(|item| !item.is_valid()) {
+            entry.inner.pop_front();
+        }
+        let now = Instant::now();
+        let mut reserved_until: Option<Instant> = None;
+        let mut best: Option<(usize, u32, Option<Instant>)> = None;
+        for (i, item) in entry.inner.iter().enumerate() {
+            if !item.is_valid() {
+                continue;
+            }
+            match item.affinity() {
+                Some((preferred, until)) if until > now && Some(preferred) != worker => {
+                    reserved_until = Some(reserved_until.map_or(until, |r| r.min(until)));
+                    continue;
+                }
+                _ => (),
             }
+            let owner = entry.owners.get(item.owner());
+            let in_flight = owner.map_or(0, |owner| owner.in_flight.load(Ordering::SeqCst));
+            if item.max_in_flight().is_some_and(|max| in_flight >= max) {
+                continue;
+            }
+            let rank = rank(item, now, aging);
+            let last_served = owner.and_then(|owner| owner.last_served);
+            if best.is_none_or(|(_, best_rank, best_last_served)| {
+                rank > best_rank || (rank == best_rank && last_served < best_last_served)
+            }) {
+                best = Some((i, rank, last_served));
+            }
+        }
+        match best.and_then(|(i, _, _)| entry.inner.remove(i)) {
+            Some(mut item) => {
+                let owner = entry.owners.entry(item.owner().clone()).or_default();
+                owner.last_served = Some(now);
+                owner.in_flight.fetch_add(1, Ordering::SeqCst);
+                item.set_in_flight(InFlight {
+                    count: Arc::clone(&owner.in_flight),
+                    signal: Arc::clone(&entry.signal),
+                });
+                entry.record_acquire();
+                Ok(item)
+            }
+            None => Err((Arc::clone(&entry.signal), reserved_until)),
         }
     }
+
+    /// Position among valid items, in the order they would be acquired,
+    /// regardless of reservations for workers.
+    fn status<P>(&self, selector: &S, mut pred: P, aging: Duration) -> Option<QueueStatus>
+    where
+        P: FnMut(&R) -> bool,
+        R: Prioritized,
+    {
+        let entry = self.map.get(selector)?;
+        let now = Instant::now();
+        let valid = entry
+            .inner
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| item.is_valid());
+        let (index, target) = valid.clone().find(|(_, item)| pred(item))?;
+        let target_rank = rank(target, now, aging);
+        let position = valid
+            .filter(|&(i, item)| {
+                let rank = rank(item, now, aging);
+                rank > target_rank || (rank == target_rank && i < index)
+            })
+            .count()
+            + 1;
+        Some(QueueStatus {
+            position,
+            estimated_wait: entry
+                .interval
+                .and_then(|interval| interval.checked_mul(u32::try_from(position).ok()?)),
+        })
+    }
+}
+
+/// Priority of the item, after promotions for waiting, up to the highest
+/// priority class.
+fn rank<R: Prioritized>(item: &R, now: Instant, aging: Duration) -> u32 {
+    let promotions = if aging.is_zero() {
+        0
+    } else {
+        let waited = now.saturating_duration_since(item.queued_at());
+        u32::try_from(waited.as_nanos() / aging.as_nanos()).unwrap_or(u32::MAX)
+    };
+    item.priority()
+        .saturating_add(promotions)
+        .min(R::MAX_PRIORITY.max(item.priority()))
 }
 
-impl<S, R: IsValid> Shard<S, R> {
+impl<S, R: IsValid + Owned> Shard<S, R> {
     fn garbage_collect(&mut self) {
         self.map.retain(|_, queue| {
             queue.inner.retain(|item| item.is_valid());
-            !queue.inner.is_empty()
+            let inner = &queue.inner;
+            queue.owners.retain(|owner, state| {
+                state.in_flight.load(Ordering::SeqCst) > 0
+                    || inner.iter().any(|item| item.owner() == owner)
+            });
+            !queue.inner.is_empty() || !queue.owners.is_empty()
         });
     }
+
+    fn len(&self) -> usize {
+        self.map.values().map(|queue| queue.inner.len()).sum()
+    }
+}
+
+/// The queue already holds the maximum number of valid items.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct QueueFull;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct QueueStatus {
+    /// 1-based position in the queue.
+    pub position: usize,
+    pub estimated_wait: Option<Duration>,
 }
 
-struct Queue<R> {
+struct Queue<R: Owned> {
     signal: Arc<Notify>,
     inner: VecDeque<R>,
+    owners: HashMap<R::Owner, OwnerState>,
+    /// Time of the last acquire that left items in the queue.
+    busy_since: Option<Instant>,
+    /// Moving average of the time between acquires while items are waiting.
+    interval: Option<Duration>,
+}
+
+#[derive(Default)]
+struct OwnerState {
+    in_flight: Arc<AtomicUsize>,
+    last_served: Option<Instant>,
+}
+
+impl<R: Owned> Queue<R> {
+    fn record_acquire(&mut self) {
+        let now = Instant::now();
+        if let Some(since) = self.busy_since {
+            let sample = now - since;
+            self.interval = Some(match self.interval {
+                Some(interval) => (interval * 7 + sample) / 8,
+                None => sample,
+            });
+        }
+        self.busy_since = (!self.inner.is_empty()).then_some(now);
+    }
 }
 
-impl<R> Default for Queue<R> {
+impl<R: Owned> Default for Queue<R> {
     fn default() -> Queue<R> {
         Queue {
             signal: Arc::new(Notify::new()),
             inner: VecDeque::new(),
+            owners: HashMap::new(),
+            busy_since: None,
+            interval: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Item(u32, bool);
+
+    impl IsValid for Item {
+        fn is_valid(&self) -> bool {
+            self.1
+        }
+    }
+
+    impl Affinity for Item {
+        type Worker = ();
+
+        fn affinity(&self) -> Option<(&(), Instant)> {
+            None
+        }
+    }
+
+    impl Prioritized for Item {
+        const MAX_PRIORITY: u32 = 0;
+
+        fn priority(&self) -> u32 {
+            0
+        }
+
+        fn queued_at(&self) -> Instant {
+            Instant::now()
+        }
+    }
+
+    impl Owned for Item {
+        type Owner = ();
+
+        fn owner(&self) -> &() {
+            &()
+        }
+
+        fn max_in_flight(&self) -> Option<usize> {
+            None
+        }
+
+        fn set_in_flight(&mut self, _in_flight: InFlight) {}
+    }
+
+    #[test]
+    fn test_status() {
+        let hub = Hub::new(1024, Duration::from_secs(10));
+        hub.submit("a", Item(1, true), |_| false).unwrap();
+        hub.submit("a", Item(2, false), |_| false).unwrap();
+        hub.submit("a", Item(3, true), |_| false).unwrap();
+        assert_eq!(
+            hub.status(&"a", |item| item.0 == 3).map(|s| s.position),
+            Some(2)
+        );
+        assert_eq!(hub.status(&"a", |item| item.0 == 2), None);
+        assert_eq!(hub.status(&"b", |item| item.0 == 1), None);
+    }
+
+    #[test]
+    fn test_flush() {
+        let hub = Hub::new(1024, Duration::from_secs(10));
+        hub.submit("a", Item(1, true), |_| false).unwrap();
+        hub.submit("a", Item(2, false), |_| false).unwrap();
+        hub.submit("b", Item(3, true), |_| false).unwrap();
+        assert_eq!(hub.flush(&"a").len(), 2);
+        let mut remaining = Vec::new();
+        hub.for_each(|selector, item| remaining.push((*selector, item.0)));
+        assert_eq!(remaining, [("b", 3)]);
+    }
+
+    #[test]
+    fn test_submit() {
+        let hub = Hub::new(2, Duration::from_secs(10));
+        hub.submit("a", Item(1, true), |_| false).unwrap();
+        hub.submit("a", Item(2, false), |_| false).unwrap();
+        hub.submit("a", Item(3, true), |_| false).unwrap();
+        assert!(matches!(
+            hub.submit("a", Item(4, true), |_| false),
+            Err(QueueFull)
+        ));
+        let superseded = hub.submit("a", Item(5, true), |item| item.0 == 1).unwrap();
+        assert_eq!(superseded.len(), 1);
+        assert_eq!(
+            hub.status(&"a", |item| item.0 == 5).map(|s| s.position),
+            Some(1)
+        );
+        hub.requeue("a", Item(7, true));
+        assert_eq!(hub.try_acquire("a", None).map(|item| item.0), Some(7));
+        hub.submit("b", Item(6, true), |_| false).unwrap();
+    }
+
+    struct Scheduled {
+        id: u32,
+        priority: u32,
+        queued_at: Instant,
+        affinity: Option<(u32, Instant)>,
+        owner: u32,
+        max_in_flight: Option<usize>,
+        in_flight: Option<InFlight>,
+    }
+
+    impl Scheduled {
+        fn new(id: u32, priority: u32) -> Scheduled {
+            Scheduled {
+                id,
+                priority,
+                queued_at: Instant::now(),
+                affinity: None,
+                owner: 0,
+                max_in_flight: None,
+                in_flight: None,
+            }
+        }
+    }
+
+    impl IsValid for Scheduled {
+        fn is_valid(&self) -> bool {
+            true
+        }
+    }
+
+    impl Affinity for Scheduled {
+        type Worker = u32;
+
+        fn affinity(&self) -> Option<(&u32, Instant)> {
+            self.affinity
+                .as_ref()
+                .map(|(worker, until)| (worker, *until))
+        }
+    }
+
+    impl Prioritized for Scheduled {
+        const MAX_PRIORITY: u32 = 2;
+
+        fn priority(&self) -> u32 {
+            self.priority
+        }
+
+        fn queued_at(&self) -> Instant {
+            self.queued_at
+        }
+    }
+
+    impl Owned for Scheduled {
+        type Owner = u32;
+
+        fn owner(&self) -> &u32 {
+            &self.owner
+        }
+
+        fn max_in_flight(&self) -> Option<usize> {
+            self.max_in_flight
+        }
+
+        fn set_in_flight(&mut self, in_flight: InFlight) {
+            self.in_flight = Some(in_flight);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_affinity() {
+        let hub = Hub::new(1024, Duration::from_secs(10));
+        let until = Instant::now() + Duration::from_secs(60);
+        for (id, affinity) in [(1, Some((1, until))), (2, Some((2, until))), (3, None)] {
+            let item = Scheduled {
+                affinity,
+                ..Scheduled::new(id, 0)
+            };
+            hub.submit("a", item, |_| false).unwrap();
+        }
+        assert_eq!(hub.acquire("a", None).await.id, 3);
+        assert_eq!(hub.acquire("a", Some(&2)).await.id, 2);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), hub.acquire("a", None))
+                .await
+                .is_err()
+        );
+        assert_eq!(hub.acquire("a", Some(&1)).await.id, 1);
+    }
+
+    #[tokio::test]
+    async fn test_supersede_wakes_waiter() {
+        let hub = Hub::new(1024, Duration::from_secs(10));
+        let capped = |id| Scheduled {
+            owner: 1,
+            max_in_flight: Some(1),
+            ..Scheduled::new(id, 0)
+        };
+        hub.submit("a", capped(1), |_| false).unwrap();
+        let _running = hub.acquire("a", None).await;
+        hub.submit("a", capped(2), |_| false).unwrap();
+        let reserved = Scheduled {
+            affinity: Some((7, Instant::now() + Duration::from_secs(60))),
+            ..Scheduled::new(3, 0)
+        };
+        hub.submit("a", reserved, |_| false).unwrap();
+        let (acquired, ()) = tokio::join!(
+            tokio::time::timeout(Duration::from_millis(500), hub.acquire("a", None)),
+            async {
+                tokio::task::yield_now().await;
+                hub.submit("a", Scheduled::new(4, 0), |item| item.id == 3)
+                    .unwrap();
+            },
+        );
+        assert_eq!(acquired.map(|item| item.id).ok(), Some(4));
+    }
+
+    #[tokio::test]
+    async fn test_priority() {
+        let hub = Hub::new(1024, Duration::from_secs(10));
+        let starving = Scheduled {
+            queued_at: Instant::now() - Duration::from_secs(25),
+            ..Scheduled::new(1, 0)
+        };
+        hub.submit("a", starving, |_| false).unwrap();
+        hub.submit("a", Scheduled::new(2, 0), |_| false).unwrap();
+        hub.submit("a", Scheduled::new(3, 1), |_| false).unwrap();
+        hub.submit("a", Scheduled::new(4, 2), |_| false).unwrap();
+        assert_eq!(
+            hub.status(&"a", |item| item.id == 2).map(|s| s.position),
+            Some(4)
+        );
+        let mut acquired = Vec::new();
+        for _ in 0..4 {
+            acquired.push(hub.acquire("a", None).await.id);
+        }
+        assert_eq!(acquired, [1, 4, 3, 2]);
+        let long_ago = Scheduled {
+            queued_at: Instant::now() - Duration::from_secs(100),
+            ..Scheduled::new(5, 0)
+        };
+        assert_eq!(rank(&long_ago, Instant::now(), Duration::from_secs(10)), 2);
+    }
+
+    #[tokio::test]
+    async fn test_fairness() {
+        let hub = Hub::new(1024, Duration::from_secs(10));
+        for (id, owner) in [(1, 1), (2, 1), (3, 1), (4, 2), (5, 3), (6, 3)] {
+            let item = Scheduled {
+                owner,
+                max_in_flight: (owner == 3).then_some(1),
+                ..Scheduled::new(id, 0)
+            };
+            hub.submit("a", item, |_| false).unwrap();
+        }
+        let mut acquired = Vec::new();
+        for _ in 0..5 {
+            acquired.push(hub.acquire("a", None).await);
+        }
+        let ids: Vec<_> = acquired.iter().map(|item| item.id).collect();
+        assert_eq!(ids, [1, 4, 5, 2, 3]);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), hub.acquire("a", None))
+                .await
+                .is_err()
+        );
+        acquired.retain(|item| item.id != 5);
+        assert_eq!(hub.acquire("a", None).await.id, 6);
+    }
+
+    #[tokio::test]
+    async fn test_fairness_with_backlog() {
+        let hub = Hub::new(1024, Duration::from_secs(10));
+        for id in 1..=5 {
+            let item = Scheduled {
+                queued_at: Instant::now() - Duration::from_secs(30),
+                owner: 1,
+                ..Scheduled::new(id, 2)
+            };
+            hub.submit("a", item, |_| false).unwrap();
+        }
+        let item = Scheduled {
+            owner: 2,
+            ..Scheduled::new(99, 2)
+        };
+        hub.submit("a", item, |_| false).unwrap();
+        let mut acquired = Vec::new();
+        for _ in 0..6 {
+            acquired.push(hub.acquire("a", None).await.id);
         }
+        assert_eq!(acquired, [1, 99, 2, 3, 4, 5]);
     }
 }
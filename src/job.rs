@@ -0,0 +1,696 @@
+use std::{
+    collections::hash_map::Entry,
+    ops::ControlFlow,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use shakmaty::variant::VariantPosition;
+use tokio::{
+    select,
+    sync::mpsc,
+    task,
+    time::{interval, sleep},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    api::{Priority, Search, Work},
+    emit::{Bestmove, Emit, ErrorKind, Event},
+    hub::{Affinity, Hub, InFlight, IsValid, Owned, Prioritized, QueueFull, QueueStatus},
+    metrics::{JobOutcome, Metrics},
+    model::{Engine, EngineId, JobId, ProviderSelector, SessionId, UserId, WorkerId},
+    ongoing::Ongoing,
+    uci::{ProtocolError, UciOut},
+    AppState, Error,
+};
+
+pub struct Job {
+    pub id: JobId,
+    pub queued_at: Instant,
+    /// Hands the output of each provider that acquires the job to the
+    /// requester.
+    tx: mpsc::UnboundedSender<mpsc::Receiver<Event>>,
+    /// Output of the provider that acquired the job.
+    events: Option<mpsc::Sender<Event>>,
+    /// Set when acquired.
+    acquired_at: Option<Instant>,
+    /// Keeps the session registered while the job is alive.
+    _session: Arc<CancellationToken>,
+    pub cancel: Arc<CancellationToken>,
+    pos: VariantPosition,
+    pub engine: Engine,
+    pub work: Work,
+    /// Worker that acquired the previous job of the session, and until when
+    /// the job is reserved for it.
+    affinity: Option<(WorkerId, Instant)>,
+    /// Set when acquired.
+    in_flight: Option<InFlight>,
+    /// Remaining attempts on other workers, if providers fail to complete
+    /// the job.
+    retries: u32,
+    selector: ProviderSelector,
+    hub: &'static Hub<ProviderSelector, Job>,
+    metrics: &'static Metrics,
+}
+
+impl Job {
+    /// Opens the stream of output from the provider that acquired the job to
+    /// the requester, unless already open.
+    pub fn start(&mut self) -> mpsc::Sender<Event> {
+        self.acquired_at.get_or_insert_with(Instant::now);
+        self.events
+            .get_or_insert_with(|| {
+                let (tx, rx) = mpsc::channel(1);
+                let _: Result<(), _> = self.tx.send(rx);
+                tx
+            })
+            .clone()
+    }
+
+    /// Time since the job was acquired.
+    fn elapsed(&self) -> Duration {
+        self.acquired_at.map_or(Duration::ZERO, |at| at.elapsed())
+    }
+
+    /// Queues the job again at the front, after its provider failed to
+    /// complete it. Once out of retries, ends the stream of the requester
+    /// with `event` instead.
+    fn abandon(&self, event: Event) {
+        if let Some(retries) = self.retries.checked_sub(1) {
+            log::warn!("queueing job {} again ({} retries left)", self.id, retries);
+            self.metrics.record_retry();
+            self.hub.requeue(
+                self.selector.clone(),
+                Job {
+                    id: JobId::random(),
+                    queued_at: self.queued_at,
+                    tx: self.tx.clone(),
+                    events: None,
+                    acquired_at: None,
+                    _session: Arc::clone(&self._session),
+                    cancel: Arc::clone(&self.cancel),
+                    pos: self.pos.clone(),
+                    engine: self.engine.clone(),
+                    work: self.work.clone(),
+                    affinity: None,
+                    in_flight: None,
+                    retries,
+                    selector: self.selector.clone(),
+                    hub: self.hub,
+                    metrics: self.metrics,
+                },
+            );
+        } else if let Some(events) = self.events.clone() {
+            task::spawn(async move {
+                let _: Result<(), _> = events.send(event).await;
+            });
+        }
+    }
+}
+
+impl IsValid for Job {
+    fn is_valid(&self) -> bool {
+        !self.tx.is_closed() && !self.cancel.is_cancelled()
+    }
+}
+
+impl Prioritized for Job {
+    const MAX_PRIORITY: u32 = Priority::Interactive as u32;
+
+    fn priority(&self) -> u32 {
+        self.work.priority() as u32
+    }
+
+    fn queued_at(&self) -> Instant {
+        self.queued_at
+    }
+}
+
+impl Owned for Job {
+    type Owner = UserId;
+
+    fn owner(&self) -> &UserId {
+        &self.engine.config.user_id
+    }
+
+    fn max_in_flight(&self) -> Option<usize> {
+        self.engine
+            .config
+            .max_concurrent_jobs
+            .and_then(|max| usize::try_from(max.get()).ok())
+    }
+
+    fn set_in_flight(&mut self, in_flight: InFlight) {
+        self.in_flight = Some(in_flight);
+    }
+}
+
+impl Affinity for Job {
+    type Worker = WorkerId;
+
+    fn affinity(&self) -> Option<(&WorkerId, Instant)> {
+        self.affinity
+            .as_ref()
+            .map(|(worker, until)| (worker, *until))
+    }
+}
+
+impl IsValid for Weak<CancellationToken> {
+    fn is_valid(&self) -> bool {
+        self.strong_count() > 0
+    }
+}
+
+/// Registered when a job is acquired, and valid until its provider is done
+/// with it.
+#[derive(Clone)]
+pub struct Running {
+    pub selector: ProviderSelector,
+    pub cancel: Weak<CancellationToken>,
+    pub events: mpsc::WeakSender<Event>,
+    pub acquired_at: Instant,
+}
+
+impl Running {
+    pub fn new(selector: ProviderSelector, job: &Job, events: &mpsc::Sender<Event>) -> Running {
+        Running {
+            selector,
+            cancel: Arc::downgrade(&job.cancel),
+            events: events.downgrade(),
+            acquired_at: Instant::now(),
+        }
+    }
+}
+
+impl IsValid for Running {
+    fn is_valid(&self) -> bool {
+        self.events.strong_count() > 0
+    }
+}
+
+/// How long to remember the worker of a session after its last job.
+const WORKER_MEMORY: Duration = Duration::from_secs(5 * 60);
+
+/// Live jobs of a session.
+#[derive(Clone)]
+pub struct Session {
+    /// Parent of the cancellation tokens of all jobs of the session.
+    pub cancel: Weak<CancellationToken>,
+    /// Cancellation token of the most recent job.
+    latest: Weak<CancellationToken>,
+    /// Worker that acquired the most recent job, and when.
+    worker: Option<(WorkerId, Instant)>,
+}
+
+impl IsValid for Session {
+    /// Valid while any job is alive, or while the worker is remembered.
+    fn is_valid(&self) -> bool {
+        self.cancel.is_valid()
+            || self
+                .worker
+                .as_ref()
+                .is_some_and(|(_, acquired_at)| acquired_at.elapsed() < WORKER_MEMORY)
+    }
+}
+
+/// Cancellation tokens and worker for a new job of a session.
+struct Joined {
+    /// Shared by all live jobs of the session.
+    session: Arc<CancellationToken>,
+    cancel: Arc<CancellationToken>,
+    /// Of the previous job of the session, if still alive.
+    previous: Option<Arc<CancellationToken>>,
+    worker: Option<WorkerId>,
+}
+
+/// Joins the session, or starts a new one.
+fn join_session(
+    sessions: &Ongoing<(EngineId, SessionId), Session>,
+    key: (EngineId, SessionId),
+) -> Joined {
+    sessions.entry(key, |entry| {
+        let (session, previous, worker) = match entry {
+            Entry::Occupied(ref entry) => {
+                let session = entry.get().cancel.upgrade().filter(|c| !c.is_cancelled());
+                let previous = session.as_ref().and_then(|_| entry.get().latest.upgrade());
+                (session, previous, entry.get().worker.clone())
+            }
+            Entry::Vacant(_) => (None, None, None),
+        };
+        let session = session.unwrap_or_else(|| Arc::new(CancellationToken::new()));
+        let cancel = Arc::new(session.child_token());
+        entry.insert_entry(Session {
+            cancel: Arc::downgrade(&session),
+            latest: Arc::downgrade(&cancel),
+            worker: worker.clone(),
+        });
+        Joined {
+            session,
+            cancel,
+            previous,
+            worker: worker.map(|(worker, _)| worker),
+        }
+    })
+}
+
+/// Remembers the worker that acquired the job, for later jobs of the same
+/// session.
+pub fn record_worker(
+    sessions: &Ongoing<(EngineId, SessionId), Session>,
+    job: &Job,
+    worker: Option<WorkerId>,
+) {
+    let Some(worker) = worker else {
+        return;
+    };
+    let key = (job.engine.id.clone(), job.work.session_id().clone());
+    sessions.entry(key, |entry| {
+        if let Entry::Occupied(mut entry) = entry {
+            entry.get_mut().worker = Some((worker, Instant::now()));
+        }
+    });
+}
+
+/// Queues sanitized work for the provider, returning the cancellation token
+/// of the new job and the stream of events for its requester. The new job
+/// takes the place of queued jobs of the same session. Fails fast if the
+/// queue of the provider is full.
+pub fn enqueue(
+    state: &AppState,
+    provider_selector: ProviderSelector,
+    engine: Engine,
+    work: Work,
+    pos: VariantPosition,
+) -> Result<(Arc<CancellationToken>, mpsc::Receiver<Event>), Error> {
+    let pickup_timeout = engine
+        .config
+        .pickup_timeout
+        .filter(|timeout| !timeout.is_zero())
+        .unwrap_or(state.opt.pickup_timeout);
+    let key = (engine.id.clone(), work.session_id().clone());
+    let Joined {
+        session,
+        cancel,
+        previous,
+        worker,
+    } = join_session(state.sessions, key.clone());
+    let queued_at = Instant::now();
+    let affinity = worker
+        .filter(|_| !state.opt.session_stickiness.is_zero())
+        .map(|worker| (worker, queued_at + state.opt.session_stickiness));
+    let (tx, rx) = mpsc::unbounded_channel();
+    let superseded = state
+        .hub
+        .submit(
+            provider_selector.clone(),
+            Job {
+                id: JobId::random(),
+                queued_at,
+                tx,
+                events: None,
+                acquired_at: None,
+                _session: session,
+                cancel: Arc::clone(&cancel),
+                engine,
+                work,
+                pos,
+                affinity,
+                in_flight: None,
+                retries: state.opt.max_retries,
+                selector: provider_selector.clone(),
+                hub: state.hub,
+                metrics: state.metrics,
+            },
+            |queued| queued.engine.id == key.0 && *queued.work.session_id() == key.1,
+        )
+        .map_err(|QueueFull| {
+            state.metrics.record_queue_full();
+            Error::ProviderOverloaded
+        })?;
+    for job in superseded {
+        job.cancel.cancel();
+    }
+    if state.opt.supersede_in_flight {
+        if let Some(previous) = previous {
+            previous.cancel();
+        }
+    }
+    let (events_tx, events_rx) = mpsc::channel(1);
+    task::spawn(track(
+        state.clone(),
+        provider_selector,
+        pickup_timeout,
+        Arc::clone(&cancel),
+        rx,
+        events_tx,
+    ));
+    Ok((cancel, events_rx))
+}
+
+/// Reports the position of a job in the queue until a provider picks it
+/// up, and then forwards the output of the provider. Starts over if the job
+/// is queued again, because the provider failed to complete it.
+async fn track(
+    state: AppState,
+    provider_selector: ProviderSelector,
+    pickup_timeout: Duration,
+    cancel: Arc<CancellationToken>,
+    mut rx: mpsc::UnboundedReceiver<mpsc::Receiver<Event>>,
+    tx: mpsc::Sender<Event>,
+) {
+    loop {
+        let deadline = sleep(pickup_timeout);
+        tokio::pin!(deadline);
+        let mut status_interval = interval(Duration::from_secs(1));
+        let mut last_status = None;
+
+        let res = loop {
+            select! {
+                res = rx.recv() => {
+                    // Jobs are dropped without being acquired only when
+                    // cancelled.
+                    break res.ok_or(Error::Cancelled);
+                }
+                _ = &mut deadline => {
+                    state.metrics.record_provider_timeout();
+                    break Err(Error::ProviderTimeout);
+                }
+                _ = cancel.cancelled() => break Err(Error::Cancelled),
+                _ = state.shutdown.cancelled() => break Err(Error::ShuttingDown),
+                _ = tx.closed() => return,
+                _ = status_interval.tick() => {
+                    let status = state
+                        .hub
+                        .status(&provider_selector, |job| Arc::ptr_eq(&job.cancel, &cancel));
+                    if let Some(QueueStatus { position, estimated_wait }) =
+                        status.filter(|_| status != last_status)
+                    {
+                        last_status = status;
+                        let event = Event::Queued { position, estimated_wait };
+                        let _: Result<(), _> = tx.send(event).await;
+                    }
+                }
+            }
+        };
+
+        let mut provider_rx = match res {
+            Ok(provider_rx) => provider_rx,
+            Err(err) => {
+                let _: Result<(), _> = tx.send(err.to_event()).await;
+                return;
+            }
+        };
+        if tx.send(Event::Started).await.is_err() {
+            return;
+        }
+        loop {
+            select! {
+                event = provider_rx.recv() => match event {
+                    Some(event) => {
+                        let last = matches!(event, Event::Bestmove(_) | Event::Error { .. });
+                        if tx.send(event).await.is_err() || last {
+                            return;
+                        }
+                    }
+                    None => break,
+                },
+                _ = cancel.cancelled() => {
+                    let _: Result<(), _> = tx.send(Error::Cancelled.to_event()).await;
+                    return;
+                }
+                _ = tx.closed() => return,
+            }
+        }
+    }
+}
+
+/// Queues acquired work again, unless the provider started submitting it
+/// before the lease expired.
+pub async fn expire_lease(
+    ongoing: &'static Ongoing<JobId, Job>,
+    id: JobId,
+    lease_timeout: Duration,
+) {
+    sleep(lease_timeout).await;
+    if let Some(job) = ongoing.remove(&id).filter(|job| !job.cancel.is_cancelled()) {
+        job.abandon(Error::LeaseExpired.to_event());
+    }
+}
+
+/// Forwards engine output of an acquired job to its requester. Unless the
+/// search is complete, the job is queued again or the requester receives an
+/// error event when the submission is dropped.
+pub struct Submission {
+    /// Also keeps the job counted against the concurrency cap of its owner.
+    job: Job,
+    search: Search,
+    tx: mpsc::Sender<Event>,
+    emit: Emit,
+    done: bool,
+    failure: Option<Event>,
+}
+
+impl Submission {
+    pub fn new(mut job: Job) -> Result<Submission, Error> {
+        if job.cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        Ok(Submission {
+            search: job.work.search().clone(),
+            tx: job.start(),
+            emit: Emit::default(),
+            done: false,
+            failure: None,
+            job,
+        })
+    }
+
+    /// Processes a line of engine output. Breaks after `bestmove`.
+    pub async fn feed(&mut self, line: &str) -> Result<ControlFlow<()>, Error> {
+        let uci = UciOut::from_line(line).map_err(|err| self.protocol_error(err))?;
+        if let Some(uci) = uci {
+            self.emit.update(&uci, &self.job.pos);
+
+            let elapsed = self.job.elapsed();
+            let bestmove =
+                Bestmove::extract(&uci, &self.emit, &self.search, &self.job.pos, elapsed)
+                    .map_err(|err| self.protocol_error(err))?;
+            if let Some(bestmove) = bestmove {
+                self.done = true;
+                let _: Result<(), _> = self.tx.send(Event::Bestmove(bestmove)).await;
+                return Ok(ControlFlow::Break(()));
+            }
+
+            if self.emit.should_emit() {
+                if self.tx.send(Event::Info(self.emit.clone())).await.is_err() {
+                    log::info!("requester suddenly gone away");
+                    self.job.cancel.cancel();
+                    return Err(Error::Cancelled);
+                }
+                self.job.metrics.record_emitted_line();
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    /// Records malformed engine output, which ends the stream of the
+    /// requester.
+    fn protocol_error(&mut self, err: ProtocolError) -> Error {
+        self.job.metrics.record_protocol_error(&err);
+        let err = Error::from(err);
+        self.failure = Some(err.to_event());
+        err
+    }
+
+    /// Resolves when the work is cancelled or the requester has gone away.
+    pub async fn cancelled(&self) {
+        select! {
+            _ = self.tx.closed() => {
+                log::info!("requester gone away");
+                self.job.cancel.cancel();
+            },
+            _ = self.job.cancel.cancelled() => log::info!("work cancelled"),
+        }
+    }
+}
+
+impl Drop for Submission {
+    fn drop(&mut self) {
+        let outcome = if self.done {
+            JobOutcome::Completed
+        } else if self.job.cancel.is_cancelled() {
+            JobOutcome::Cancelled
+        } else {
+            JobOutcome::Failed
+        };
+        self.job.metrics.observe_job(outcome, self.job.elapsed());
+        if self.done {
+            return;
+        }
+        let event = match self.failure.take() {
+            Some(failure) => failure,
+            None if self.job.cancel.is_cancelled() => Error::Cancelled.to_event(),
+            None => {
+                // Another worker may well complete the job.
+                self.job.abandon(Event::Error {
+                    error: ErrorKind::ProviderDisconnected,
+                    message: "provider disconnected before bestmove".to_owned(),
+                });
+                return;
+            }
+        };
+        let tx = self.tx.clone();
+        task::spawn(async move {
+            let _: Result<(), _> = tx.send(event).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::Chess;
+
+    use super::*;
+    use crate::model::ProviderSecret;
+
+    fn job(retries: u32) -> (Job, mpsc::UnboundedReceiver<mpsc::Receiver<Event>>) {
+        let hub: &'static Hub<ProviderSelector, Job> =
+            Box::leak(Box::new(Hub::new(16, Duration::from_secs(10))));
+        let metrics: &'static Metrics = Box::leak(Box::default());
+        let secret: ProviderSecret = serde_json::from_str(r#""secret""#).unwrap();
+        let selector = secret.selector();
+        let engine = Engine {
+            id: EngineId("eei_test".to_owned()),
+            config: serde_json::from_str(
+                r#"{
+                    "name": "Stockfish",
+                    "clientSecret": "ees_test",
+                    "userId": "someone",
+                    "maxThreads": 8,
+                    "maxHash": 1024,
+                    "variants": ["chess"]
+                }"#,
+            )
+            .unwrap(),
+        };
+        let work: Work = serde_json::from_str(
+            r#"{
+                "sessionId": "abc",
+                "threads": 1,
+                "hash": 16,
+                "movetime": 1000,
+                "multiPv": 1,
+                "variant": "chess",
+                "initialFen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "moves": []
+            }"#,
+        )
+        .unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let session = Arc::new(CancellationToken::new());
+        let job = Job {
+            id: JobId::random(),
+            queued_at: Instant::now(),
+            tx,
+            events: None,
+            acquired_at: None,
+            cancel: Arc::new(session.child_token()),
+            _session: session,
+            pos: VariantPosition::Chess(Chess::default()),
+            engine,
+            work,
+            affinity: None,
+            in_flight: None,
+            retries,
+            selector,
+            hub,
+            metrics,
+        };
+        (job, rx)
+    }
+
+    #[tokio::test]
+    async fn test_abandon() {
+        let (mut job, mut rx) = job(1);
+        job.start();
+        let mut first = rx.recv().await.unwrap();
+        job.abandon(Error::LeaseExpired.to_event());
+
+        let mut requeued = job
+            .hub
+            .try_acquire(job.selector.clone(), None)
+            .expect("queued again");
+        assert_ne!(requeued.id, job.id);
+        assert_eq!(requeued.retries, 0);
+        assert!(requeued.events.is_none());
+        drop(job);
+        assert!(first.recv().await.is_none());
+
+        requeued.start();
+        let mut second = rx.recv().await.unwrap();
+        requeued.abandon(Error::LeaseExpired.to_event());
+        assert!(requeued
+            .hub
+            .try_acquire(requeued.selector.clone(), None)
+            .is_none());
+        assert!(matches!(
+            second.recv().await,
+            Some(Event::Error {
+                error: ErrorKind::Timeout,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_expire_lease() {
+        let ongoing: &'static Ongoing<JobId, Job> = Box::leak(Box::default());
+        let (mut job, mut rx) = job(0);
+        job.start();
+        let mut events = rx.recv().await.unwrap();
+        let id = job.id.clone();
+        ongoing.add(id.clone(), job);
+
+        expire_lease(ongoing, id.clone(), Duration::ZERO).await;
+        assert!(ongoing.remove(&id).is_none());
+        assert!(matches!(
+            events.recv().await,
+            Some(Event::Error {
+                error: ErrorKind::Timeout,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_expire_cancelled_lease() {
+        let ongoing: &'static Ongoing<JobId, Job> = Box::leak(Box::default());
+        let (mut job, mut rx) = job(1);
+        job.start();
+        let mut events = rx.recv().await.unwrap();
+        let (hub, selector) = (job.hub, job.selector.clone());
+        let id = job.id.clone();
+        job.cancel.cancel();
+        ongoing.add(id.clone(), job);
+
+        expire_lease(ongoing, id, Duration::ZERO).await;
+        assert!(hub.try_acquire(selector, None).is_none());
+        assert!(events.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_drop_submission() {
+        let (job, mut rx) = job(0);
+        let submission = Submission::new(job).unwrap();
+        let mut events = rx.recv().await.unwrap();
+        drop(submission);
+        assert!(matches!(
+            events.recv().await,
+            Some(Event::Error {
+                error: ErrorKind::ProviderDisconnected,
+                ..
+            })
+        ));
+    }
+}
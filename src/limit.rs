@@ -0,0 +1,206 @@
+use std::{
+    convert::Infallible,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
+use tokio::join;
+
+use crate::{
+    hub::IsValid,
+    metrics::{Metrics, RateLimit},
+    model::{Engine, EngineId, ProviderSelector, UserId},
+    ongoing::Ongoing,
+    AppState, Error, Opt,
+};
+
+/// Token bucket parameters, given as `<burst>/<seconds>`. For example,
+/// `30/60` allows bursts of up to 30 requests, refilling at 30 requests per
+/// minute.
+#[derive(Debug, Copy, Clone)]
+pub struct Rate {
+    burst: f64,
+    per_token: Duration,
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rate, String> {
+        let invalid = || format!("invalid rate (expected <burst>/<seconds>): {s}");
+        let (burst, secs) = s.split_once('/').ok_or_else(invalid)?;
+        let burst: u32 = burst.parse().map_err(|_| invalid())?;
+        let secs: f64 = secs.parse().map_err(|_| invalid())?;
+        if burst == 0 {
+            return Err(invalid());
+        }
+        Ok(Rate {
+            burst: f64::from(burst),
+            per_token: Duration::try_from_secs_f64(secs / f64::from(burst))
+                .map_err(|_| invalid())?,
+        })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    full_at: Instant,
+}
+
+impl IsValid for Bucket {
+    /// Full buckets can be forgotten.
+    fn is_valid(&self) -> bool {
+        Instant::now() < self.full_at
+    }
+}
+
+struct RateLimiter<K> {
+    rate: Option<Rate>,
+    buckets: Ongoing<K, Bucket>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    fn new(rate: Option<Rate>) -> RateLimiter<K> {
+        RateLimiter {
+            rate,
+            buckets: Ongoing::default(),
+        }
+    }
+
+    /// Takes a token, or tells how long until the next token is available.
+    fn check(&self, key: K) -> Result<(), Duration> {
+        let Some(rate) = self.rate else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        self.buckets.entry(key, |entry| {
+            let bucket = entry.or_insert_with(|| Bucket {
+                tokens: rate.burst,
+                updated_at: now,
+                full_at: now,
+            });
+            let refilled = (now - bucket.updated_at).as_secs_f64() / rate.per_token.as_secs_f64();
+            bucket.tokens = f64::min(rate.burst, bucket.tokens + refilled);
+            bucket.updated_at = now;
+            if bucket.tokens < 1.0 {
+                return Err(rate.per_token.mul_f64(1.0 - bucket.tokens));
+            }
+            bucket.tokens -= 1.0;
+            bucket.full_at = now + rate.per_token.mul_f64(rate.burst - bucket.tokens);
+            Ok(())
+        })
+    }
+}
+
+/// Rate limits for requesters and providers.
+pub(crate) struct Limits {
+    engine: RateLimiter<EngineId>,
+    user: RateLimiter<UserId>,
+    ip: RateLimiter<IpAddr>,
+    provider: RateLimiter<ProviderSelector>,
+    metrics: &'static Metrics,
+}
+
+impl Limits {
+    pub fn new(opt: &Opt, metrics: &'static Metrics) -> Limits {
+        Limits {
+            engine: RateLimiter::new(opt.engine_rate_limit),
+            user: RateLimiter::new(opt.user_rate_limit),
+            ip: RateLimiter::new(opt.ip_rate_limit),
+            provider: RateLimiter::new(opt.provider_rate_limit),
+            metrics,
+        }
+    }
+
+    /// Limits analysis requests by remote address, if known.
+    pub fn check_ip(&self, ip: Option<IpAddr>) -> Result<(), Error> {
+        match ip {
+            Some(ip) => self.record(RateLimit::Ip, self.ip.check(ip)),
+            None => Ok(()),
+        }
+    }
+
+    /// Limits analysis requests by engine and by its owner.
+    pub fn check_engine(&self, engine: &Engine) -> Result<(), Error> {
+        self.record(RateLimit::Engine, self.engine.check(engine.id.clone()))?;
+        self.record(
+            RateLimit::User,
+            self.user.check(engine.config.user_id.clone()),
+        )
+    }
+
+    /// Limits acquire requests by provider.
+    pub fn check_provider(&self, selector: &ProviderSelector) -> Result<(), Error> {
+        self.record(RateLimit::Provider, self.provider.check(selector.clone()))
+    }
+
+    fn record(&self, limit: RateLimit, res: Result<(), Duration>) -> Result<(), Error> {
+        res.map_err(|retry_after| {
+            self.metrics.record_rate_limited(limit);
+            Error::RateLimited(retry_after)
+        })
+    }
+
+    pub async fn garbage_collect(&self, interval: Duration) {
+        join!(
+            self.engine.buckets.garbage_collect(interval),
+            self.user.buckets.garbage_collect(interval),
+            self.ip.buckets.garbage_collect(interval),
+            self.provider.buckets.garbage_collect(interval),
+        );
+    }
+}
+
+/// Remote address of the requester: the last address in the configured
+/// header from a trusted reverse proxy, or else the peer address, if any.
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<ClientIp, Infallible> {
+        Ok(ClientIp(match state.opt.real_ip_header {
+            Some(ref header) => parts
+                .headers
+                .get_all(header)
+                .iter()
+                .next_back()
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.rsplit(',').next())
+                .and_then(|ip| ip.trim().parse().ok()),
+            None => parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip()),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(Some("2/60".parse().unwrap()));
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_ok());
+        let retry_after = limiter.check("a").unwrap_err();
+        assert!(retry_after > Duration::from_secs(29) && retry_after <= Duration::from_secs(30));
+        assert!(limiter.check("b").is_ok());
+
+        assert!(RateLimiter::new(None).check("a").is_ok());
+        assert!("0/60".parse::<Rate>().is_err());
+        assert!("60".parse::<Rate>().is_err());
+    }
+}
@@ -1,10 +1,24 @@
-use std::{convert::Infallible, io, net::SocketAddr, path::PathBuf, time::Duration};
+use std::{
+    cmp::min,
+    convert::Infallible,
+    future::IntoFuture,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use axum::{
     body::Body,
     extract::{FromRef, Json, State},
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    http::{
+        header::{ACCEPT, CONTENT_TYPE, RETRY_AFTER},
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
+    },
+    response::{
+        sse::{self, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     Router,
 };
 use axum_extra::{
@@ -12,46 +26,51 @@ use axum_extra::{
     json_lines::JsonLines,
     routing::{RouterExt, TypedPath},
 };
+use axum_server::{tls_rustls::RustlsConfig, Handle};
 use clap::{builder::PathBufValueParser, Parser};
-use futures::Stream;
 use futures_util::stream::{StreamExt, TryStreamExt};
 use listenfd::ListenFd;
 use serde::Deserialize;
-use shakmaty::variant::VariantPosition;
 use thiserror::Error;
 use tikv_jemallocator::Jemalloc;
 use tokio::{
     io::AsyncBufReadExt,
     net::{TcpListener, UnixListener},
     select,
-    sync::{
-        mpsc,
-        oneshot::{self, error::RecvError},
-    },
-    task,
-    time::{error::Elapsed, timeout},
+    signal::unix::{signal, SignalKind},
+    task::{self, JoinHandle},
+    time::{error::Elapsed, sleep, timeout},
 };
 use tokio_stream::wrappers::ReceiverStream;
-use tokio_util::io::StreamReader;
+use tokio_util::{io::StreamReader, sync::CancellationToken};
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use crate::{
-    api::{AcquireRequest, AcquireResponse, AnalyseRequest, InvalidWorkError, Work},
-    emit::Emit,
-    hub::{Hub, IsValid},
-    model::{Engine, EngineId, JobId, ProviderSelector},
+    api::{AcquireRequest, AcquireResponse, AnalyseRequest, CancelRequest, InvalidWorkError},
+    emit::{ErrorKind, Event},
+    hub::Hub,
+    job::{enqueue, expire_lease, record_worker, Job, Running, Session, Submission},
+    limit::{ClientIp, Limits, Rate},
+    metrics::{AcquireOutcome, Metrics},
+    model::{EngineId, JobId, ProviderSelector, SessionId},
     ongoing::Ongoing,
-    repo::Repo,
-    uci::UciOut,
+    repo::{Cache, Repo, RepoError},
+    uci::ProtocolError,
 };
 
+mod admin;
 mod api;
 mod emit;
+mod engines;
 mod hub;
+mod job;
+mod limit;
+mod metrics;
 mod model;
 mod ongoing;
 mod repo;
+mod socket;
 mod uci;
 
 #[global_allocator]
@@ -65,32 +84,187 @@ struct Opt {
     /// Database.
     #[arg(long, default_value = "mongodb://localhost", env = "LILA_ENGINE_MONGODB")]
     pub mongodb: String,
-    /// Certificate file for HTTPS server.
-    #[arg(long, value_parser = PathBufValueParser::new(), env = "LILA_ENGINE_CERT_PEM")]
+    /// Engine repository: mongodb://..., file:engines.toml, file:engines.json
+    /// or memory:. Defaults to the database.
+    #[arg(long, env = "LILA_ENGINE_REPO")]
+    pub repo: Option<String>,
+    /// Binding address for HTTPS, if a certificate and private key are given.
+    #[arg(long, default_value = "127.0.0.1:9667", env = "LILA_ENGINE_BIND_HTTPS")]
+    pub bind_https: SocketAddr,
+    /// Certificate file for HTTPS server. Reloaded on SIGHUP.
+    #[arg(
+        long,
+        value_parser = PathBufValueParser::new(),
+        requires = "key_pem",
+        env = "LILA_ENGINE_CERT_PEM"
+    )]
     pub cert_pem: Option<PathBuf>,
-    /// Private key for HTTPS server.
-    #[arg(long, value_parser = PathBufValueParser::new(), env = "LILA_ENGINE_KEY_PEM")]
+    /// Private key for HTTPS server. Reloaded on SIGHUP.
+    #[arg(
+        long,
+        value_parser = PathBufValueParser::new(),
+        requires = "cert_pem",
+        env = "LILA_ENGINE_KEY_PEM"
+    )]
     pub key_pem: Option<PathBuf>,
+    /// Seconds to wait for a provider to pick up work, unless overridden by
+    /// the engine.
+    #[arg(
+        long,
+        default_value = "15",
+        value_parser = parse_positive_seconds,
+        env = "LILA_ENGINE_PICKUP_TIMEOUT"
+    )]
+    pub pickup_timeout: Duration,
+    /// Seconds that providers long-poll for work or cancellation.
+    #[arg(
+        long,
+        default_value = "10",
+        value_parser = parse_positive_seconds,
+        env = "LILA_ENGINE_ACQUIRE_TIMEOUT"
+    )]
+    pub acquire_timeout: Duration,
+    /// Seconds between garbage collecting two shards of queued work.
+    #[arg(
+        long,
+        default_value = "13",
+        value_parser = parse_positive_seconds,
+        env = "LILA_ENGINE_HUB_GC_INTERVAL"
+    )]
+    pub hub_gc_interval: Duration,
+    /// Seconds between garbage collecting two shards of acquired work.
+    #[arg(
+        long,
+        default_value = "7",
+        value_parser = parse_positive_seconds,
+        env = "LILA_ENGINE_ONGOING_GC_INTERVAL"
+    )]
+    pub ongoing_gc_interval: Duration,
+    /// Seconds to let acquired work finish after SIGTERM.
+    #[arg(
+        long,
+        default_value = "30",
+        value_parser = parse_seconds,
+        env = "LILA_ENGINE_SHUTDOWN_TIMEOUT"
+    )]
+    pub shutdown_timeout: Duration,
+    /// Bearer token for the admin API. The admin API is disabled if not set.
+    #[arg(long, env = "LILA_ENGINE_ADMIN_SECRET")]
+    pub admin_secret: Option<String>,
+    /// Key for user tokens of the engine management API. The engine
+    /// management API is disabled if not set.
+    #[arg(long, env = "LILA_ENGINE_USER_SECRET")]
+    pub user_secret: Option<String>,
+    /// Seconds to cache engines after looking them up. Changes are picked up
+    /// sooner if the database supports change streams.
+    #[arg(
+        long,
+        default_value = "60",
+        value_parser = parse_seconds,
+        env = "LILA_ENGINE_ENGINE_CACHE_TTL"
+    )]
+    pub engine_cache_ttl: Duration,
+    /// Seconds to remember that an engine was not found.
+    #[arg(
+        long,
+        default_value = "5",
+        value_parser = parse_seconds,
+        env = "LILA_ENGINE_ENGINE_NEGATIVE_CACHE_TTL"
+    )]
+    pub engine_negative_cache_ttl: Duration,
+    /// Rate limit for analysis requests per engine, as `<burst>/<seconds>`,
+    /// e.g. `30/60`. Unlimited if not set.
+    #[arg(long, env = "LILA_ENGINE_ENGINE_RATE_LIMIT")]
+    pub engine_rate_limit: Option<Rate>,
+    /// Rate limit for analysis requests per engine owner.
+    #[arg(long, env = "LILA_ENGINE_USER_RATE_LIMIT")]
+    pub user_rate_limit: Option<Rate>,
+    /// Rate limit for analysis requests per remote address.
+    #[arg(long, env = "LILA_ENGINE_IP_RATE_LIMIT")]
+    pub ip_rate_limit: Option<Rate>,
+    /// Rate limit for acquire requests per provider.
+    #[arg(long, env = "LILA_ENGINE_PROVIDER_RATE_LIMIT")]
+    pub provider_rate_limit: Option<Rate>,
+    /// Header with the remote address, set by a trusted reverse proxy, e.g.
+    /// X-Forwarded-For. Defaults to the peer address.
+    #[arg(long, env = "LILA_ENGINE_REAL_IP_HEADER")]
+    pub real_ip_header: Option<HeaderName>,
+    /// Let new work of a session also cancel work that providers already
+    /// acquired, not only queued work.
+    #[arg(long, env = "LILA_ENGINE_SUPERSEDE_IN_FLIGHT")]
+    pub supersede_in_flight: bool,
+    /// Seconds that new work of a session is reserved for the worker that
+    /// acquired the previous work of the session, if the provider gives
+    /// worker ids. Disabled with 0.
+    #[arg(
+        long,
+        default_value = "1",
+        value_parser = parse_seconds,
+        env = "LILA_ENGINE_SESSION_STICKINESS"
+    )]
+    pub session_stickiness: Duration,
+    /// Seconds of waiting after which queued work is promoted to the next
+    /// priority class, so that batch work is not starved.
+    #[arg(
+        long,
+        default_value = "10",
+        value_parser = parse_seconds,
+        env = "LILA_ENGINE_PRIORITY_AGING"
+    )]
+    pub priority_aging: Duration,
+    /// Maximum number of queued jobs per provider.
+    #[arg(long, default_value = "1024", env = "LILA_ENGINE_MAX_QUEUE")]
+    pub max_queue: usize,
+    /// Seconds for providers to start submitting acquired work, before it is
+    /// queued again for another worker.
+    #[arg(
+        long,
+        default_value = "10",
+        value_parser = parse_positive_seconds,
+        env = "LILA_ENGINE_LEASE_TIMEOUT"
+    )]
+    pub lease_timeout: Duration,
+    /// How many times to queue work again after providers failed to
+    /// complete it.
+    #[arg(long, default_value = "2", env = "LILA_ENGINE_MAX_RETRIES")]
+    pub max_retries: u32,
 }
 
-struct Job {
-    tx: oneshot::Sender<mpsc::Receiver<Emit>>,
-    pos: VariantPosition,
-    engine: Engine,
-    work: Work,
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    s.parse()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("invalid number of seconds: {s}"))
 }
 
-impl IsValid for Job {
-    fn is_valid(&self) -> bool {
-        !self.tx.is_closed()
-    }
+fn parse_positive_seconds(s: &str) -> Result<Duration, String> {
+    parse_seconds(s).and_then(|duration| {
+        if duration.is_zero() {
+            Err(format!("expected more than 0 seconds: {s}"))
+        } else {
+            Ok(duration)
+        }
+    })
 }
 
 #[derive(Clone)]
 struct AppState {
+    opt: &'static Opt,
     repo: &'static Repo,
     hub: &'static Hub<ProviderSelector, Job>,
     ongoing: &'static Ongoing<JobId, Job>,
+    sessions: &'static Ongoing<(EngineId, SessionId), Session>,
+    running: &'static Ongoing<JobId, Running>,
+    shutdown: &'static CancellationToken,
+    metrics: &'static Metrics,
+    limits: &'static Limits,
+    garbage_collectors: &'static [JoinHandle<()>],
+}
+
+impl FromRef<AppState> for &'static Opt {
+    fn from_ref(state: &AppState) -> &'static Opt {
+        state.opt
+    }
 }
 
 impl FromRef<AppState> for &'static Repo {
@@ -111,10 +285,46 @@ impl FromRef<AppState> for &'static Ongoing<JobId, Job> {
     }
 }
 
+impl FromRef<AppState> for &'static Ongoing<(EngineId, SessionId), Session> {
+    fn from_ref(state: &AppState) -> &'static Ongoing<(EngineId, SessionId), Session> {
+        state.sessions
+    }
+}
+
+impl FromRef<AppState> for &'static Ongoing<JobId, Running> {
+    fn from_ref(state: &AppState) -> &'static Ongoing<JobId, Running> {
+        state.running
+    }
+}
+
+impl FromRef<AppState> for &'static CancellationToken {
+    fn from_ref(state: &AppState) -> &'static CancellationToken {
+        state.shutdown
+    }
+}
+
+impl FromRef<AppState> for &'static Metrics {
+    fn from_ref(state: &AppState) -> &'static Metrics {
+        state.metrics
+    }
+}
+
+impl FromRef<AppState> for &'static Limits {
+    fn from_ref(state: &AppState) -> &'static Limits {
+        state.limits
+    }
+}
+
+impl FromRef<AppState> for &'static [JoinHandle<()>] {
+    fn from_ref(state: &AppState) -> &'static [JoinHandle<()>] {
+        state.garbage_collectors
+    }
+}
+
 #[derive(Error, Debug)]
 enum Error {
-    #[error("mongodb error: {0}")]
-    MongoDb(#[from] mongodb::error::Error),
+    #[error("repository error: {0}")]
+    Repo(#[from] RepoError),
     #[error("engine not found or invalid clientSecret")]
     EngineNotFound,
     #[error("work not found or cancelled or expired")]
@@ -122,24 +332,99 @@ enum Error {
     #[error("i/o error: {0}")]
     Io(#[from] io::Error),
     #[error("uci protocol error: {0}")]
-    Protocol(#[from] uci::ProtocolError),
+    Protocol(#[from] ProtocolError),
     #[error("invalid work: {0}")]
     InvalidWork(#[from] InvalidWorkError),
-    #[error("recv: {0}")]
-    Recv(#[from] RecvError),
     #[error("provider did not pick up work")]
     ProviderTimeout,
+    #[error("provider did not submit acquired work in time")]
+    LeaseExpired,
+    #[error("provider overloaded, too much queued work")]
+    ProviderOverloaded,
+    #[error("work cancelled")]
+    Cancelled,
+    #[error("server restarting")]
+    ShuttingDown,
+    #[error("missing or invalid authorization")]
+    Unauthorized,
+    #[error("too many requests, retry in {}s", retry_after_secs(*.0))]
+    RateLimited(Duration),
+    #[error("invalid registration: {0}")]
+    InvalidRegistration(&'static str),
+    #[error("invalid json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("websocket error: {0}")]
+    WebSocket(#[from] axum::Error),
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
         let status = match self {
-            Error::MongoDb(_) | Error::Recv(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            Error::Io(_) | Error::Protocol(_) | Error::InvalidWork(_) => StatusCode::BAD_REQUEST,
+            Error::Repo(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Io(_)
+            | Error::Protocol(_)
+            | Error::InvalidWork(_)
+            | Error::Json(_)
+            | Error::WebSocket(_)
+            | Error::InvalidRegistration(_) => StatusCode::BAD_REQUEST,
             Error::EngineNotFound | Error::WorkNotFound => StatusCode::NOT_FOUND,
-            Error::ProviderTimeout => StatusCode::SERVICE_UNAVAILABLE,
+            Error::ProviderTimeout
+            | Error::LeaseExpired
+            | Error::ProviderOverloaded
+            | Error::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Cancelled => StatusCode::GONE,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+        };
+        let retry_after = match self {
+            Error::RateLimited(retry_after) => Some(retry_after),
+            Error::ProviderOverloaded => Some(PROVIDER_OVERLOADED_RETRY_AFTER),
+            _ => None,
         };
-        (status, self.to_string()).into_response()
+        let mut response = match self {
+            // Distinguishable from other 503 responses.
+            Error::ProviderOverloaded => (status, Json(self.to_event())).into_response(),
+            _ => (status, self.to_string()).into_response(),
+        };
+        if let Some(retry_after) = retry_after {
+            response.headers_mut().insert(
+                RETRY_AFTER,
+                HeaderValue::from(retry_after_secs(retry_after)),
+            );
+        }
+        response
+    }
+}
+
+/// Suggested delay before queueing work again, when the queue of the
+/// provider was full.
+const PROVIDER_OVERLOADED_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs_f64().ceil() as u64
+}
+
+impl Error {
+    /// Describes the error for the requester of a job, at the end of its
+    /// stream.
+    fn to_event(&self) -> Event {
+        Event::Error {
+            error: match self {
+                Error::InvalidWork(_) | Error::Json(_) | Error::InvalidRegistration(_) => {
+                    ErrorKind::InvalidWork
+                }
+                Error::ProviderTimeout | Error::LeaseExpired => ErrorKind::Timeout,
+                Error::ProviderOverloaded => ErrorKind::ProviderOverloaded,
+                Error::Cancelled => ErrorKind::Cancelled,
+                Error::ShuttingDown => ErrorKind::Restarting,
+                Error::RateLimited(_) => ErrorKind::RateLimited,
+                Error::Protocol(_) => ErrorKind::Protocol,
+                Error::Io(_) | Error::WebSocket(_) => ErrorKind::ProviderDisconnected,
+                Error::EngineNotFound => ErrorKind::EngineNotFound,
+                Error::Repo(_) | Error::WorkNotFound | Error::Unauthorized => ErrorKind::Internal,
+            },
+            message: self.to_string(),
+        }
     }
 }
 
@@ -153,37 +438,215 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer().without_time())
         .init();
 
-    let opt = Opt::parse();
+    let opt: &'static Opt = Box::leak(Box::new(Opt::parse()));
+    let metrics: &'static Metrics = Box::leak(Box::default());
+
+    let hub = Box::leak(Box::new(Hub::new(opt.max_queue, opt.priority_aging)));
+    let ongoing = Box::leak(Box::new(Ongoing::default()));
+    let sessions = Box::leak(Box::new(Ongoing::default()));
+    let running = Box::leak(Box::new(Ongoing::default()));
+    let repo: &'static Repo = Box::leak(Box::new(
+        Repo::new(
+            opt.repo.as_deref().unwrap_or(&opt.mongodb),
+            Cache::new(opt.engine_cache_ttl, opt.engine_negative_cache_ttl),
+            metrics,
+        )
+        .await,
+    ));
+    task::spawn(repo.watch());
+    let limits: &'static Limits = Box::leak(Box::new(Limits::new(opt, metrics)));
+
+    let garbage_collectors = Box::leak(Box::new([
+        task::spawn(hub.garbage_collect(opt.hub_gc_interval)),
+        task::spawn(ongoing.garbage_collect(opt.ongoing_gc_interval)),
+        task::spawn(sessions.garbage_collect(opt.ongoing_gc_interval)),
+        task::spawn(running.garbage_collect(opt.ongoing_gc_interval)),
+        task::spawn(repo.garbage_collect(opt.ongoing_gc_interval)),
+        task::spawn(limits.garbage_collect(opt.ongoing_gc_interval)),
+    ]));
 
     let state = AppState {
-        repo: Box::leak(Box::new(Repo::new(&opt.mongodb).await)),
-        hub: Box::leak(Box::new(Hub::default())),
-        ongoing: Box::leak(Box::new(Ongoing::default())),
+        opt,
+        repo,
+        hub,
+        ongoing,
+        sessions,
+        running,
+        shutdown: Box::leak(Box::new(CancellationToken::new())),
+        metrics,
+        limits,
+        garbage_collectors,
     };
 
-    task::spawn(state.hub.garbage_collect());
-    task::spawn(state.ongoing.garbage_collect());
+    let stopped = CancellationToken::new();
+    task::spawn(shutdown_on_terminate(
+        opt,
+        state.shutdown,
+        state.running,
+        stopped.clone(),
+    ));
 
     let app = Router::new()
         .typed_post(analyse)
+        .typed_delete(cancel)
+        .typed_get(socket::analyse_socket)
         .typed_post(acquire)
         .typed_post(submit)
+        .typed_get(status)
+        .typed_get(socket::provider_socket)
+        .typed_get(metrics_endpoint)
+        .typed_get(healthz)
+        .typed_get(readyz)
+        .typed_get(engines::list)
+        .typed_post(engines::create)
+        .typed_get(engines::get)
+        .typed_put(engines::update)
+        .typed_delete(engines::delete)
+        .typed_post(engines::rotate_client_secret)
+        .typed_get(admin::queues)
+        .typed_delete(admin::flush)
+        .typed_delete(admin::evict)
+        .typed_post(admin::issue_user_token)
         .layer(CorsLayer::permissive().max_age(Duration::from_secs(60 * 60 * 24)))
         .layer(TraceLayer::new_for_http())
         .with_state(state);
 
     let mut fds = ListenFd::from_env();
+    let https = if let (Some(cert_pem), Some(key_pem)) = (&opt.cert_pem, &opt.key_pem) {
+        let config = RustlsConfig::from_pem_file(cert_pem, key_pem)
+            .await
+            .expect("tls config");
+        task::spawn(reload_tls_on_hangup(config.clone(), cert_pem, key_pem));
+        let tcp = match fds.take_tcp_listener(1) {
+            Ok(Some(tcp)) => tcp,
+            _ => std::net::TcpListener::bind(opt.bind_https).expect("bind https"),
+        };
+        let server = axum_server::from_tcp_rustls(tcp, config);
+        let handle = Handle::new();
+        task::spawn({
+            let handle = handle.clone();
+            let stopped = stopped.clone();
+            async move {
+                stopped.cancelled().await;
+                handle.graceful_shutdown(Some(CLOSE_TIMEOUT));
+            }
+        });
+        let app = app.clone();
+        Some(task::spawn(async move {
+            server
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .expect("serve https");
+        }))
+    } else {
+        None
+    };
     if let Ok(Some(uds)) = fds.take_unix_listener(0) {
         uds.set_nonblocking(true).expect("set nonblocking");
         let listener = UnixListener::from_std(uds).expect("listener");
-        axum::serve(listener, app).await.expect("serve");
+        serve_until_stopped(
+            axum::serve(listener, app).with_graceful_shutdown(stopped.clone().cancelled_owned()),
+            &stopped,
+        )
+        .await;
     } else if let Ok(Some(tcp)) = fds.take_tcp_listener(0) {
         tcp.set_nonblocking(true).expect("set nonblocking");
         let listener = TcpListener::from_std(tcp).expect("listener");
-        axum::serve(listener, app).await.expect("serve");
+        serve_until_stopped(
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(stopped.clone().cancelled_owned()),
+            &stopped,
+        )
+        .await;
     } else {
         let listener = TcpListener::bind(&opt.bind).await.expect("bind");
-        axum::serve(listener, app).await.expect("serve");
+        serve_until_stopped(
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(stopped.clone().cancelled_owned()),
+            &stopped,
+        )
+        .await;
+    }
+    if let Some(https) = https {
+        https.await.expect("https server");
+    }
+}
+
+/// How long servers keep open connections after stopping, to deliver final
+/// events.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Runs a server with graceful shutdown, but closes connections that are
+/// still open `CLOSE_TIMEOUT` after it stopped.
+async fn serve_until_stopped<F>(server: F, stopped: &CancellationToken)
+where
+    F: IntoFuture<Output = io::Result<()>>,
+{
+    let server = server.into_future();
+    tokio::pin!(server);
+    select! {
+        res = &mut server => res.expect("serve"),
+        _ = stopped.cancelled() => {
+            if let Ok(res) = timeout(CLOSE_TIMEOUT, server).await {
+                res.expect("serve");
+            } else {
+                log::warn!("closing remaining connections");
+            }
+        }
+    }
+}
+
+/// On SIGTERM, stops handing out work and waits for acquired work to
+/// finish, at most until the deadline. Then ends remaining work with a
+/// `restarting` error and stops the servers.
+async fn shutdown_on_terminate(
+    opt: &Opt,
+    shutdown: &CancellationToken,
+    running: &Ongoing<JobId, Running>,
+    stopped: CancellationToken,
+) {
+    let mut terminate = signal(SignalKind::terminate()).expect("sigterm handler");
+    terminate.recv().await;
+    log::info!("shutting down, draining ongoing work");
+    shutdown.cancel();
+    let drained = async {
+        while !running.is_empty() {
+            sleep(Duration::from_millis(100)).await;
+        }
+    };
+    if timeout(opt.shutdown_timeout, drained).await.is_err() {
+        log::warn!("shutdown deadline exceeded, ending ongoing work");
+        let mut remaining = Vec::new();
+        running.for_each(|_, running| {
+            remaining.extend(running.events.upgrade().zip(running.cancel.upgrade()));
+        });
+        let restarting = async {
+            for (events, _) in &remaining {
+                let _: Result<(), _> = events.send(Error::ShuttingDown.to_event()).await;
+            }
+        };
+        let _: Result<(), _> = timeout(CLOSE_TIMEOUT, restarting).await;
+        for (_, cancel) in remaining {
+            cancel.cancel();
+        }
+    }
+    stopped.cancel();
+}
+
+async fn reload_tls_on_hangup(config: RustlsConfig, cert_pem: &Path, key_pem: &Path) {
+    let mut hangup = signal(SignalKind::hangup()).expect("sighup handler");
+    while hangup.recv().await.is_some() {
+        match config.reload_from_pem_file(cert_pem, key_pem).await {
+            Ok(()) => log::info!("reloaded tls certificate"),
+            Err(err) => log::error!("failed to reload tls certificate: {err}"),
+        }
     }
 }
 
@@ -196,33 +659,69 @@ struct AnalysePath {
 #[axum_macros::debug_handler(state = AppState)]
 async fn analyse(
     AnalysePath { id }: AnalysePath,
-    State(hub): State<&'static Hub<ProviderSelector, Job>>,
-    State(repo): State<&'static Repo>,
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
     Json(req): Json<AnalyseRequest>,
-) -> Result<JsonLines<impl Stream<Item = Result<Emit, Infallible>>, json_lines::AsResponse>, Error>
-{
-    let (engine, provider_selector) = repo
+) -> Result<Response, Error> {
+    state.limits.check_ip(ip)?;
+    let (engine, provider_selector) = state
+        .repo
         .find(id, req.client_secret)
         .await?
         .ok_or(Error::EngineNotFound)?
         .into_engine_and_selector();
+    state.limits.check_engine(&engine)?;
     let (work, pos) = req.work.sanitize(&engine)?;
-    let (tx, rx) = oneshot::channel();
-    hub.submit(
-        provider_selector,
-        Job {
-            tx,
-            engine,
-            work,
-            pos,
-        },
-    );
-    let rx = timeout(Duration::from_secs(15), rx)
-        .await
-        .map_err(|_: Elapsed| Error::ProviderTimeout)??;
-    Ok(JsonLines::new(
-        ReceiverStream::new(rx).map(Ok::<_, Infallible>),
-    ))
+    let (_, events) = enqueue(&state, provider_selector, engine, work, pos)?;
+    let events = ReceiverStream::new(events);
+    Ok(if accepts_event_stream(&headers) {
+        Sse::new(events.map(|event| {
+            let sse = sse::Event::default();
+            match event.name() {
+                Some(name) => sse.event(name),
+                None => sse,
+            }
+            .json_data(&event)
+        }))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+    } else {
+        JsonLines::<_, json_lines::AsResponse>::new(events.map(Ok::<_, Infallible>)).into_response()
+    })
+}
+
+fn accepts_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("text/event-stream"))
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/{id}/analyse/{session_id}")]
+struct CancelPath {
+    id: EngineId,
+    session_id: SessionId,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn cancel(
+    CancelPath { id, session_id }: CancelPath,
+    State(repo): State<&'static Repo>,
+    State(sessions): State<&'static Ongoing<(EngineId, SessionId), Session>>,
+    Json(req): Json<CancelRequest>,
+) -> Result<StatusCode, Error> {
+    repo.find(id.clone(), req.client_secret)
+        .await?
+        .ok_or(Error::EngineNotFound)?;
+    sessions
+        .remove(&(id, session_id))
+        .and_then(|session| session.cancel.upgrade())
+        .ok_or(Error::WorkNotFound)?
+        .cancel();
+    Ok(StatusCode::NO_CONTENT)
 }
 
 #[derive(TypedPath, Deserialize)]
@@ -231,6 +730,9 @@ struct AcquirePath;
 
 struct AcquireTimeout;
 
+/// Upper bound for `maxJobs` of a single acquire request.
+const MAX_ACQUIRE_JOBS: usize = 64;
+
 impl IntoResponse for AcquireTimeout {
     fn into_response(self) -> Response {
         StatusCode::NO_CONTENT.into_response()
@@ -240,22 +742,64 @@ impl IntoResponse for AcquireTimeout {
 #[axum_macros::debug_handler(state = AppState)]
 async fn acquire(
     _: AcquirePath,
-    State(hub): State<&'static Hub<ProviderSelector, Job>>,
-    State(ongoing): State<&'static Ongoing<JobId, Job>>,
+    State(state): State<AppState>,
     Json(req): Json<AcquireRequest>,
-) -> Result<Json<AcquireResponse>, AcquireTimeout> {
+) -> Result<Response, Error> {
     let selector = req.provider_secret.selector();
-    let job = timeout(Duration::from_secs(10), hub.acquire(selector))
-        .await
-        .map_err(|_: Elapsed| AcquireTimeout)?;
-    let id = JobId::random();
-    let response = AcquireResponse {
-        id: id.clone(),
-        engine: job.engine.clone(),
-        work: job.work.clone(),
+    state.limits.check_provider(&selector)?;
+    let job = select! {
+        // Hand out no more work once shutting down, even if some is queued.
+        biased;
+        _ = state.shutdown.cancelled() => {
+            state.metrics.record_acquire(AcquireOutcome::ShuttingDown);
+            return Err(Error::ShuttingDown);
+        }
+        job = state.hub.acquire(selector.clone(), req.worker_id.as_ref()) => job,
+        _ = sleep(state.opt.acquire_timeout) => {
+            state.metrics.record_acquire(AcquireOutcome::Timeout);
+            return Ok(AcquireTimeout.into_response());
+        }
     };
-    ongoing.add(id, job);
-    Ok(Json(response))
+    let mut jobs = vec![job];
+    if let Some(max_jobs) = req.max_jobs {
+        let max_jobs = min(max_jobs.get(), MAX_ACQUIRE_JOBS);
+        while jobs.len() < max_jobs {
+            match state
+                .hub
+                .try_acquire(selector.clone(), req.worker_id.as_ref())
+            {
+                Some(job) => jobs.push(job),
+                None => break,
+            }
+        }
+    }
+    state.metrics.record_acquire(AcquireOutcome::Job);
+    let mut responses = Vec::with_capacity(jobs.len());
+    for mut job in jobs {
+        state.metrics.record_acquired_job();
+        record_worker(state.sessions, &job, req.worker_id.clone());
+        responses.push(AcquireResponse {
+            id: job.id.clone(),
+            engine: job.engine.clone(),
+            work: job.work.clone(),
+        });
+        let events = job.start();
+        state.running.add(
+            job.id.clone(),
+            Running::new(selector.clone(), &job, &events),
+        );
+        task::spawn(expire_lease(
+            state.ongoing,
+            job.id.clone(),
+            state.opt.lease_timeout,
+        ));
+        state.ongoing.add(job.id.clone(), job);
+    }
+    Ok(if req.max_jobs.is_some() {
+        Json(responses).into_response()
+    } else {
+        Json(responses.swap_remove(0)).into_response()
+    })
 }
 
 #[derive(TypedPath, Deserialize)]
@@ -270,9 +814,7 @@ async fn submit(
     State(ongoing): State<&'static Ongoing<JobId, Job>>,
     body: Body,
 ) -> Result<(), Error> {
-    let work = ongoing.remove(&id).ok_or(Error::WorkNotFound)?;
-    let (tx, rx) = mpsc::channel(1);
-    let _: Result<(), _> = work.tx.send(rx);
+    let mut submission = Submission::new(ongoing.remove(&id).ok_or(Error::WorkNotFound)?)?;
 
     let stream = body
         .into_data_stream()
@@ -280,27 +822,93 @@ async fn submit(
     let read = StreamReader::new(stream);
     let mut lines = read.lines();
 
-    let mut emit = Emit::default();
-
     while let Some(line) = select! {
         maybe_line = lines.next_line() => maybe_line?,
-        _ = tx.closed() => {
-            log::info!("requester gone away");
-            None
-        },
+        _ = submission.cancelled() => return Err(Error::Cancelled),
     } {
-        if let Some(uci) = UciOut::from_line(&line)? {
-            emit.update(&uci, &work.pos);
-
-            if matches!(uci, UciOut::Bestmove { .. }) {
-                break;
-            }
-
-            if emit.should_emit() && tx.send(emit.clone()).await.is_err() {
-                log::info!("requester suddenly gone away");
-                break;
-            }
+        if submission.feed(&line).await?.is_break() {
+            break;
         }
     }
     Ok(())
 }
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/healthz")]
+struct HealthzPath;
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn healthz(_: HealthzPath) -> StatusCode {
+    StatusCode::NO_CONTENT
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/readyz")]
+struct ReadyzPath;
+
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Ready to serve requests, unless shutting down, garbage collection
+/// stopped, or the repository is unreachable.
+#[axum_macros::debug_handler(state = AppState)]
+async fn readyz(
+    _: ReadyzPath,
+    State(repo): State<&'static Repo>,
+    State(shutdown): State<&'static CancellationToken>,
+    State(garbage_collectors): State<&'static [JoinHandle<()>]>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let unavailable = |reason: String| (StatusCode::SERVICE_UNAVAILABLE, reason);
+    if shutdown.is_cancelled() {
+        return Err(unavailable("shutting down".to_owned()));
+    }
+    if garbage_collectors.iter().any(JoinHandle::is_finished) {
+        return Err(unavailable("garbage collection stopped".to_owned()));
+    }
+    match timeout(READY_TIMEOUT, repo.ping()).await {
+        Ok(Ok(())) => Ok(StatusCode::NO_CONTENT),
+        Ok(Err(err)) => Err(unavailable(err.to_string())),
+        Err(Elapsed { .. }) => Err(unavailable("repository ping timed out".to_owned())),
+    }
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/metrics")]
+struct MetricsPath;
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn metrics_endpoint(
+    _: MetricsPath,
+    State(hub): State<&'static Hub<ProviderSelector, Job>>,
+    State(running): State<&'static Ongoing<JobId, Running>>,
+    State(metrics): State<&'static Metrics>,
+) -> impl IntoResponse {
+    (
+        [(
+            CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        metrics.encode(hub.queued(), running.len()),
+    )
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/work/{id}/status")]
+struct StatusPath {
+    id: JobId,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn status(
+    StatusPath { id }: StatusPath,
+    State(opt): State<&'static Opt>,
+    State(running): State<&'static Ongoing<JobId, Running>>,
+) -> Result<StatusCode, Error> {
+    let cancel = running
+        .get(&id)
+        .and_then(|running| running.cancel.upgrade())
+        .ok_or(Error::WorkNotFound)?;
+    match timeout(opt.acquire_timeout, cancel.cancelled()).await {
+        Ok(()) => Err(Error::Cancelled),
+        Err(Elapsed { .. }) => Ok(StatusCode::NO_CONTENT),
+    }
+}
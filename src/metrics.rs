@@ -0,0 +1,263 @@
+use std::time::Duration;
+
+use prometheus_client::{
+    encoding::{text::encode, EncodeLabelSet, EncodeLabelValue},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+
+use crate::uci::ProtocolError;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum AcquireOutcome {
+    Job,
+    Timeout,
+    ShuttingDown,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum JobOutcome {
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum RateLimit {
+    Engine,
+    User,
+    Ip,
+    Provider,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+enum ProtocolErrorKind {
+    UnexpectedToken,
+    UnexpectedLineBreak,
+    UnexpectedEndOfLine,
+    InvalidMove,
+    IllegalMove,
+    InvalidInteger,
+    InvalidMultipv,
+}
+
+impl From<&ProtocolError> for ProtocolErrorKind {
+    fn from(err: &ProtocolError) -> ProtocolErrorKind {
+        match err {
+            ProtocolError::UnexpectedToken => ProtocolErrorKind::UnexpectedToken,
+            ProtocolError::UnexpectedLineBreak => ProtocolErrorKind::UnexpectedLineBreak,
+            ProtocolError::UnexpectedEndOfLine => ProtocolErrorKind::UnexpectedEndOfLine,
+            ProtocolError::InvalidMove(_) => ProtocolErrorKind::InvalidMove,
+            ProtocolError::IllegalMove(_) => ProtocolErrorKind::IllegalMove,
+            ProtocolError::InvalidInteger(_) => ProtocolErrorKind::InvalidInteger,
+            ProtocolError::InvalidMultipv(_) => ProtocolErrorKind::InvalidMultipv,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ShardLabels {
+    shard: usize,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct AcquireLabels {
+    outcome: AcquireOutcome,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct JobLabels {
+    outcome: JobOutcome,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RateLimitLabels {
+    limit: RateLimit,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ProtocolErrorLabels {
+    kind: ProtocolErrorKind,
+}
+
+/// Prometheus metrics. All labels take values from small, fixed sets.
+pub struct Metrics {
+    registry: Registry,
+    queued: Family<ShardLabels, Gauge>,
+    in_flight: Gauge,
+    acquires: Family<AcquireLabels, Counter>,
+    acquired_jobs: Counter,
+    provider_timeouts: Counter,
+    queue_full: Counter,
+    retries: Counter,
+    repo_find: Histogram,
+    emitted_lines: Counter,
+    protocol_errors: Family<ProtocolErrorLabels, Counter>,
+    jobs: Family<JobLabels, Histogram, fn() -> Histogram>,
+    rate_limited: Family<RateLimitLabels, Counter>,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        let mut registry = Registry::with_prefix("lila_engine");
+
+        let queued = Family::default();
+        registry.register(
+            "queued_jobs",
+            "Queued jobs per hub shard, including jobs not yet garbage collected",
+            queued.clone(),
+        );
+        let in_flight = Gauge::default();
+        registry.register(
+            "in_flight_jobs",
+            "Jobs acquired by providers and not yet finished",
+            in_flight.clone(),
+        );
+        let acquires = Family::default();
+        registry.register("acquires", "Outcomes of acquire requests", acquires.clone());
+        let acquired_jobs = Counter::default();
+        registry.register(
+            "acquired_jobs",
+            "Jobs handed out to providers",
+            acquired_jobs.clone(),
+        );
+        let provider_timeouts = Counter::default();
+        registry.register(
+            "provider_timeouts",
+            "Jobs not picked up by a provider in time",
+            provider_timeouts.clone(),
+        );
+        let queue_full = Counter::default();
+        registry.register(
+            "queue_full",
+            "Jobs rejected because the queue of the provider was full",
+            queue_full.clone(),
+        );
+        let retries = Counter::default();
+        registry.register(
+            "retries",
+            "Jobs queued again after a provider failed to complete them",
+            retries.clone(),
+        );
+        let repo_find = Histogram::new(exponential_buckets(0.001, 2.0, 12));
+        registry.register(
+            "repo_find_seconds",
+            "Latency of engine lookups",
+            repo_find.clone(),
+        );
+        let emitted_lines = Counter::default();
+        registry.register(
+            "emitted_lines",
+            "Analysis lines sent to requesters",
+            emitted_lines.clone(),
+        );
+        let protocol_errors = Family::default();
+        registry.register(
+            "protocol_errors",
+            "Malformed engine output from providers",
+            protocol_errors.clone(),
+        );
+        let jobs = Family::<_, _, fn() -> Histogram>::new_with_constructor(|| {
+            Histogram::new(exponential_buckets(0.1, 2.0, 12))
+        });
+        registry.register(
+            "job_duration_seconds",
+            "Duration of jobs from acquire to end of search",
+            jobs.clone(),
+        );
+
+        let rate_limited = Family::default();
+        registry.register(
+            "rate_limited",
+            "Requests rejected by rate limits",
+            rate_limited.clone(),
+        );
+
+        Metrics {
+            registry,
+            queued,
+            in_flight,
+            acquires,
+            acquired_jobs,
+            provider_timeouts,
+            queue_full,
+            retries,
+            repo_find,
+            emitted_lines,
+            protocol_errors,
+            jobs,
+            rate_limited,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record_acquire(&self, outcome: AcquireOutcome) {
+        self.acquires
+            .get_or_create(&AcquireLabels { outcome })
+            .inc();
+    }
+
+    pub fn record_acquired_job(&self) {
+        self.acquired_jobs.inc();
+    }
+
+    pub fn record_provider_timeout(&self) {
+        self.provider_timeouts.inc();
+    }
+
+    pub fn record_queue_full(&self) {
+        self.queue_full.inc();
+    }
+
+    pub fn record_retry(&self) {
+        self.retries.inc();
+    }
+
+    pub fn observe_repo_find(&self, duration: Duration) {
+        self.repo_find.observe(duration.as_secs_f64());
+    }
+
+    pub fn record_emitted_line(&self) {
+        self.emitted_lines.inc();
+    }
+
+    pub fn record_protocol_error(&self, err: &ProtocolError) {
+        let kind = ProtocolErrorKind::from(err);
+        self.protocol_errors
+            .get_or_create(&ProtocolErrorLabels { kind })
+            .inc();
+    }
+
+    pub fn observe_job(&self, outcome: JobOutcome, duration: Duration) {
+        self.jobs
+            .get_or_create(&JobLabels { outcome })
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn record_rate_limited(&self, limit: RateLimit) {
+        self.rate_limited
+            .get_or_create(&RateLimitLabels { limit })
+            .inc();
+    }
+
+    /// Encodes all metrics in the OpenMetrics text format, after sampling
+    /// the given gauges.
+    pub fn encode(&self, queued: impl Iterator<Item = usize>, in_flight: usize) -> String {
+        for (shard, len) in queued.enumerate() {
+            self.queued
+                .get_or_create(&ShardLabels { shard })
+                .set(len as i64);
+        }
+        self.in_flight.set(in_flight as i64);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &self.registry).expect("encode metrics");
+        buffer
+    }
+}
@@ -1,17 +1,25 @@
+use rand::{
+    distr::{Alphanumeric, SampleString},
+    rng,
+};
 use serde::{Deserialize, Serialize};
 
+use crate::model::secret_eq;
+
 #[derive(Deserialize, Serialize, Debug, Eq, Clone)]
 pub struct ClientSecret(String);
 
+impl ClientSecret {
+    pub fn random() -> ClientSecret {
+        ClientSecret(format!(
+            "ees_{}",
+            Alphanumeric.sample_string(&mut rng(), 16)
+        ))
+    }
+}
+
 impl PartialEq for ClientSecret {
     fn eq(&self, other: &ClientSecret) -> bool {
-        // Best effort constant time equality
-        self.0.len() == other.0.len()
-            && self
-                .0
-                .bytes()
-                .zip(other.0.bytes())
-                .fold(0, |acc, (left, right)| acc | (left ^ right))
-                == 0
+        secret_eq(&self.0, &other.0)
     }
 }
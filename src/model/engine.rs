@@ -1,12 +1,16 @@
-use std::{fmt, num::NonZeroU32};
+use std::{fmt, num::NonZeroU32, time::Duration};
 
+use rand::{
+    distr::{Alphanumeric, SampleString},
+    rng,
+};
 use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, FromInto};
+use serde_with::{serde_as, DurationSeconds, FromInto};
 use shakmaty::variant::Variant;
 
 use crate::model::{ClientSecret, UciVariant, UserId};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct EngineId(pub String);
 
 impl fmt::Display for EngineId {
@@ -15,6 +19,15 @@ impl fmt::Display for EngineId {
     }
 }
 
+impl EngineId {
+    pub fn random() -> EngineId {
+        EngineId(format!(
+            "eei_{}",
+            Alphanumeric.sample_string(&mut rng(), 16)
+        ))
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct Engine {
     pub id: EngineId,
@@ -34,4 +47,11 @@ pub struct EngineConfig {
     #[serde_as(as = "Vec<FromInto<UciVariant>>")]
     pub variants: Vec<Variant>,
     pub provider_data: Option<String>,
+    /// Overrides how long requests wait for a provider to pick up work.
+    /// Ignored if 0.
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub pickup_timeout: Option<Duration>,
+    /// Caps how many jobs of the owner a provider runs at once, across all
+    /// engines of the owner that share the provider.
+    pub max_concurrent_jobs: Option<NonZeroU32>,
 }
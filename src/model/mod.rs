@@ -1,3 +1,5 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 
 mod client_secret;
@@ -14,8 +16,34 @@ pub use multi_pv::{InvalidMultiPvError, MultiPv};
 pub use provider_secret::{ProviderSecret, ProviderSelector};
 pub use uci_variant::UciVariant;
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct UserId(String);
+/// Best effort constant time equality, for comparing secrets.
+pub fn secret_eq(left: &str, right: &str) -> bool {
+    left.len() == right.len()
+        && left
+            .bytes()
+            .zip(right.bytes())
+            .fold(0, |acc, (left, right)| acc | (left ^ right))
+            == 0
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UserId(pub String);
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct SessionId(String);
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Identifies an engine process of a provider, as chosen by the provider.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WorkerId(String);
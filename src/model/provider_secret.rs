@@ -1,4 +1,6 @@
-use serde::Deserialize;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 #[derive(Deserialize, Debug)]
@@ -13,5 +15,11 @@ impl ProviderSecret {
     }
 }
 
-#[derive(Deserialize, Eq, PartialEq, Hash, Debug, Clone)]
+#[derive(Deserialize, Serialize, Eq, PartialEq, Hash, Debug, Clone)]
 pub struct ProviderSelector(String);
+
+impl fmt::Display for ProviderSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
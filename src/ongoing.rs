@@ -1,6 +1,9 @@
 use std::{
     array,
-    collections::{hash_map::RandomState, HashMap},
+    collections::{
+        hash_map::{Entry, RandomState},
+        HashMap,
+    },
     hash::{BuildHasher, Hash},
     sync::Mutex,
     time::Duration,
@@ -35,17 +38,63 @@ impl<S: Hash + Eq, R> Ongoing<S, R> {
         self.shard(selector).lock().unwrap().remove(selector)
     }
 
+    pub fn get(&self, selector: &S) -> Option<R>
+    where
+        R: Clone,
+    {
+        self.shard(selector).lock().unwrap().get(selector).cloned()
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().clear();
+        }
+    }
+
+    pub fn entry<T>(&self, selector: S, f: impl FnOnce(Entry<'_, S, R>) -> T) -> T {
+        f(self.shard(&selector).lock().unwrap().entry(selector))
+    }
+
     fn shard(&self, selector: &S) -> &Mutex<HashMap<S, R>> {
         &self.shards[self.random_state.hash_one(selector) as usize % NUM_SHARDS]
     }
 }
 
 impl<S, R: IsValid> Ongoing<S, R> {
-    pub async fn garbage_collect(&self) {
+    /// Checks that there are no valid items, regardless of when garbage was
+    /// last collected.
+    pub fn is_empty(&self) -> bool {
+        self.shards
+            .iter()
+            .all(|shard| !shard.lock().unwrap().values().any(|item| item.is_valid()))
+    }
+
+    /// Visits all valid items.
+    pub fn for_each(&self, mut f: impl FnMut(&S, &R)) {
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            for (selector, item) in shard.iter().filter(|(_, item)| item.is_valid()) {
+                f(selector, item);
+            }
+        }
+    }
+
+    /// Counts valid items, regardless of when garbage was last collected.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let shard = shard.lock().unwrap();
+                shard.values().filter(|item| item.is_valid()).count()
+            })
+            .sum()
+    }
+
+    pub async fn garbage_collect(&self, interval: Duration) {
         loop {
             for shard in &self.shards {
                 shard.lock().unwrap().retain(|_, item| item.is_valid());
-                sleep(Duration::from_secs(7)).await;
+                sleep(interval).await;
             }
         }
     }
@@ -0,0 +1,84 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{hub::IsValid, model::EngineId, ongoing::Ongoing, repo::ExternalEngine};
+
+#[derive(Clone)]
+struct Cached {
+    engine: Option<ExternalEngine>,
+    expires_at: Instant,
+}
+
+impl IsValid for Cached {
+    fn is_valid(&self) -> bool {
+        Instant::now() < self.expires_at
+    }
+}
+
+/// Recently looked up engines, including engines that were not found.
+pub struct Cache {
+    ttl: Duration,
+    negative_ttl: Duration,
+    entries: Ongoing<EngineId, Cached>,
+    /// Incremented on every invalidation, so that lookups that started
+    /// before a change do not cache stale results.
+    generation: AtomicU64,
+}
+
+impl Cache {
+    pub fn new(ttl: Duration, negative_ttl: Duration) -> Cache {
+        Cache {
+            ttl,
+            negative_ttl,
+            entries: Ongoing::default(),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// `Some(None)` if the engine is known not to exist.
+    pub fn get(&self, id: &EngineId) -> Option<Option<ExternalEngine>> {
+        self.entries
+            .get(id)
+            .filter(Cached::is_valid)
+            .map(|cached| cached.engine)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Caches the result of a lookup that started at `generation`, unless
+    /// anything was invalidated in the meantime.
+    pub fn insert(&self, generation: u64, id: EngineId, engine: Option<ExternalEngine>) {
+        let ttl = if engine.is_some() {
+            self.ttl
+        } else {
+            self.negative_ttl
+        };
+        if ttl.is_zero() {
+            return;
+        }
+        let expires_at = Instant::now() + ttl;
+        self.entries.entry(id, |entry| {
+            if self.generation() == generation {
+                entry.insert_entry(Cached { engine, expires_at });
+            }
+        });
+    }
+
+    pub fn invalidate(&self, id: &EngineId) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.entries.remove(id);
+    }
+
+    pub fn clear(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.entries.clear();
+    }
+
+    pub async fn garbage_collect(&self, interval: Duration) {
+        self.entries.garbage_collect(interval).await
+    }
+}
@@ -0,0 +1,166 @@
+use std::{collections::HashMap, fs, io, path::Path, sync::RwLock};
+
+use futures::future::{self, BoxFuture, FutureExt};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    model::{EngineConfig, EngineId, ProviderSecret, UserId},
+    repo::{Backend, Cache, ExternalEngine, RepoError},
+};
+
+/// Engine as defined in a file. Unlike in the database, providers are
+/// identified by their secret, not its hash.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct FileEngine {
+    id: EngineId,
+    provider_secret: ProviderSecret,
+    #[serde(flatten)]
+    config: EngineConfig,
+}
+
+#[derive(Deserialize, Debug)]
+struct EngineFile {
+    #[serde(default)]
+    engines: Vec<FileEngine>,
+}
+
+#[derive(Error, Debug)]
+pub enum FileError {
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid toml: {0}")]
+    Toml(#[from] toml::de::Error),
+}
+
+/// Keeps engines in memory, optionally loaded from a file at startup.
+/// Changes are not written back to the file.
+#[derive(Default)]
+pub struct Memory {
+    engines: RwLock<HashMap<EngineId, ExternalEngine>>,
+}
+
+impl Memory {
+    pub fn from_file(path: &Path) -> Result<Memory, FileError> {
+        let contents = fs::read_to_string(path)?;
+        let file: EngineFile = if path.extension().is_some_and(|ext| ext == "toml") {
+            toml::from_str(&contents)?
+        } else {
+            serde_json::from_str(&contents)?
+        };
+        Ok(Memory::from(file))
+    }
+}
+
+impl From<EngineFile> for Memory {
+    fn from(file: EngineFile) -> Memory {
+        Memory {
+            engines: RwLock::new(
+                file.engines
+                    .into_iter()
+                    .map(|engine| {
+                        let external = ExternalEngine {
+                            id: engine.id.clone(),
+                            provider_selector: engine.provider_secret.selector(),
+                            config: engine.config,
+                        };
+                        (engine.id, external)
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl Backend for Memory {
+    fn find(
+        &'static self,
+        id: EngineId,
+    ) -> BoxFuture<'static, Result<Option<ExternalEngine>, RepoError>> {
+        future::ready(Ok(self.engines.read().unwrap().get(&id).cloned())).boxed()
+    }
+
+    fn list(
+        &'static self,
+        user_id: UserId,
+    ) -> BoxFuture<'static, Result<Vec<ExternalEngine>, RepoError>> {
+        let engines = self.engines.read().unwrap();
+        let owned = engines
+            .values()
+            .filter(|engine| engine.config.user_id == user_id)
+            .cloned()
+            .collect();
+        future::ready(Ok(owned)).boxed()
+    }
+
+    fn insert(&'static self, engine: ExternalEngine) -> BoxFuture<'static, Result<(), RepoError>> {
+        self.engines
+            .write()
+            .unwrap()
+            .insert(engine.id.clone(), engine);
+        future::ready(Ok(())).boxed()
+    }
+
+    fn update(&'static self, engine: ExternalEngine) -> BoxFuture<'static, Result<(), RepoError>> {
+        if let Some(existing) = self.engines.write().unwrap().get_mut(&engine.id) {
+            *existing = engine;
+        }
+        future::ready(Ok(())).boxed()
+    }
+
+    fn delete(&'static self, id: EngineId) -> BoxFuture<'static, Result<(), RepoError>> {
+        self.engines.write().unwrap().remove(&id);
+        future::ready(Ok(())).boxed()
+    }
+
+    fn ping(&'static self) -> BoxFuture<'static, Result<(), RepoError>> {
+        future::ready(Ok(())).boxed()
+    }
+
+    fn watch(&'static self, _cache: &'static Cache) -> BoxFuture<'static, Result<(), RepoError>> {
+        future::pending().boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_toml() {
+        let file: EngineFile = toml::from_str(
+            r#"
+            [[engines]]
+            id = "eei_local"
+            providerSecret = "secret"
+            name = "Stockfish"
+            clientSecret = "ees_local"
+            userId = "someone"
+            maxThreads = 8
+            maxHash = 1024
+            variants = ["chess", "antichess"]
+            pickupTimeout = 30
+            "#,
+        )
+        .unwrap();
+        let memory: &'static Memory = Box::leak(Box::new(Memory::from(file)));
+
+        let engine = memory
+            .find(EngineId("eei_local".to_owned()))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(engine.config.max_threads.get(), 8);
+        let secret: ProviderSecret = serde_json::from_str(r#""secret""#).unwrap();
+        assert_eq!(engine.provider_selector, secret.selector());
+
+        assert!(memory
+            .find(EngineId("eei_other".to_owned()))
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
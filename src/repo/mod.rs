@@ -0,0 +1,186 @@
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::time::sleep;
+
+use crate::{
+    metrics::Metrics,
+    model::{ClientSecret, Engine, EngineConfig, EngineId, ProviderSelector, UserId},
+};
+
+mod cache;
+mod memory;
+mod mongo;
+
+pub use cache::Cache;
+pub use memory::Memory;
+pub use mongo::Mongo;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExternalEngine {
+    #[serde(rename = "_id")]
+    pub id: EngineId,
+    pub provider_selector: ProviderSelector,
+    #[serde(flatten)]
+    pub config: EngineConfig,
+}
+
+impl ExternalEngine {
+    pub fn into_engine_and_selector(self) -> (Engine, ProviderSelector) {
+        (
+            Engine {
+                id: self.id,
+                config: self.config,
+            },
+            self.provider_selector,
+        )
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RepoError {
+    #[error("mongodb error: {0}")]
+    MongoDb(#[from] mongodb::error::Error),
+    #[error("bson error: {0}")]
+    Bson(#[from] mongodb::bson::ser::Error),
+}
+
+/// Storage of external engines.
+pub trait Backend: Send + Sync {
+    fn find(
+        &'static self,
+        id: EngineId,
+    ) -> BoxFuture<'static, Result<Option<ExternalEngine>, RepoError>>;
+
+    fn list(
+        &'static self,
+        user_id: UserId,
+    ) -> BoxFuture<'static, Result<Vec<ExternalEngine>, RepoError>>;
+
+    fn insert(&'static self, engine: ExternalEngine) -> BoxFuture<'static, Result<(), RepoError>>;
+
+    /// Replaces the engine with the same id, if any.
+    fn update(&'static self, engine: ExternalEngine) -> BoxFuture<'static, Result<(), RepoError>>;
+
+    fn delete(&'static self, id: EngineId) -> BoxFuture<'static, Result<(), RepoError>>;
+
+    fn ping(&'static self) -> BoxFuture<'static, Result<(), RepoError>>;
+
+    /// Invalidates cached engines as they are changed by anyone, until the
+    /// change notifications end or fail. Never resolves for backends that
+    /// cannot be changed other than through this process.
+    fn watch(&'static self, cache: &'static Cache) -> BoxFuture<'static, Result<(), RepoError>>;
+}
+
+/// Delay before watching for changes again, relying only on the cache TTL
+/// in the meantime.
+const WATCH_RETRY: Duration = Duration::from_secs(60);
+
+pub struct Repo {
+    backend: Box<dyn Backend>,
+    cache: Cache,
+    metrics: &'static Metrics,
+}
+
+impl Repo {
+    /// Selects the backend by URL scheme: `mongodb://` or `mongodb+srv://`,
+    /// `file:` with a `.json` or `.toml` file, or `memory:`.
+    pub async fn new(url: &str, cache: Cache, metrics: &'static Metrics) -> Repo {
+        let backend: Box<dyn Backend> = if let Some(path) = url
+            .strip_prefix("file://")
+            .or_else(|| url.strip_prefix("file:"))
+        {
+            Box::new(Memory::from_file(Path::new(path)).expect("engine file"))
+        } else if url == "memory:" {
+            Box::new(Memory::default())
+        } else {
+            Box::new(Mongo::new(url).await)
+        };
+        Repo {
+            backend,
+            cache,
+            metrics,
+        }
+    }
+
+    pub async fn watch(&'static self) {
+        loop {
+            match self.backend.watch(&self.cache).await {
+                Ok(()) => log::warn!("engine change stream ended"),
+                Err(err) => log::warn!("engine change stream unavailable, relying on ttl: {err}"),
+            }
+            sleep(WATCH_RETRY).await;
+        }
+    }
+
+    pub async fn garbage_collect(&'static self, interval: Duration) {
+        self.cache.garbage_collect(interval).await
+    }
+
+    pub async fn ping(&'static self) -> Result<(), RepoError> {
+        self.backend.ping().await
+    }
+
+    pub async fn find(
+        &'static self,
+        id: EngineId,
+        client_secret: ClientSecret,
+    ) -> Result<Option<ExternalEngine>, RepoError> {
+        let engine = match self.cache.get(&id) {
+            Some(engine) => engine,
+            None => {
+                let generation = self.cache.generation();
+                let started = Instant::now();
+                let res = self.backend.find(id.clone()).await;
+                self.metrics.observe_repo_find(started.elapsed());
+                let engine = res?;
+                self.cache.insert(generation, id, engine.clone());
+                engine
+            }
+        };
+        Ok(engine.filter(|e| e.config.client_secret == client_secret))
+    }
+
+    /// Finds an engine that belongs to the given user.
+    pub async fn find_owned(
+        &'static self,
+        id: EngineId,
+        user_id: &UserId,
+    ) -> Result<Option<ExternalEngine>, RepoError> {
+        Ok(self
+            .backend
+            .find(id)
+            .await?
+            .filter(|e| e.config.user_id == *user_id))
+    }
+
+    pub async fn list(&'static self, user_id: UserId) -> Result<Vec<ExternalEngine>, RepoError> {
+        self.backend.list(user_id).await
+    }
+
+    pub async fn insert(&'static self, engine: ExternalEngine) -> Result<(), RepoError> {
+        let id = engine.id.clone();
+        let res = self.backend.insert(engine).await;
+        self.cache.invalidate(&id);
+        res
+    }
+
+    pub async fn update(&'static self, engine: ExternalEngine) -> Result<(), RepoError> {
+        let id = engine.id.clone();
+        let res = self.backend.update(engine).await;
+        self.cache.invalidate(&id);
+        res
+    }
+
+    pub async fn delete(&'static self, id: EngineId) -> Result<(), RepoError> {
+        let res = self.backend.delete(id.clone()).await;
+        self.cache.invalidate(&id);
+        res
+    }
+}
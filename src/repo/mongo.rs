@@ -0,0 +1,135 @@
+use std::future::Future;
+
+use futures::{future::BoxFuture, TryStreamExt};
+use mongodb::{
+    bson::{doc, to_bson, to_document},
+    options::ClientOptions,
+    Client, Collection, Database,
+};
+use tokio::task;
+
+use crate::{
+    model::{EngineId, UserId},
+    repo::{Backend, Cache, ExternalEngine, RepoError},
+};
+
+pub struct Mongo {
+    db: Database,
+    coll: Collection<ExternalEngine>,
+}
+
+impl Mongo {
+    pub async fn new(url: &str) -> Mongo {
+        let client =
+            Client::with_options(ClientOptions::parse(url).await.expect("mongodb options"))
+                .expect("mongodb client");
+
+        let db = client
+            .default_database()
+            .unwrap_or_else(|| client.database("lichess"));
+
+        Mongo {
+            coll: db.collection("external_engine"),
+            db,
+        }
+    }
+}
+
+/// Runs a database operation to completion on its own task, even if the
+/// caller goes away, because the MongoDB driver does not support
+/// cancellation.
+async fn uncancellable<T: Send + 'static>(
+    operation: impl Future<Output = T> + Send + 'static,
+) -> T {
+    task::spawn(operation)
+        .await
+        .expect("join mongodb operation")
+}
+
+impl Backend for Mongo {
+    fn find(
+        &'static self,
+        id: EngineId,
+    ) -> BoxFuture<'static, Result<Option<ExternalEngine>, RepoError>> {
+        Box::pin(async move {
+            uncancellable(async move { self.coll.find_one(doc! { "_id": id.0 }).await })
+                .await
+                .map_err(RepoError::from)
+        })
+    }
+
+    fn list(
+        &'static self,
+        user_id: UserId,
+    ) -> BoxFuture<'static, Result<Vec<ExternalEngine>, RepoError>> {
+        Box::pin(async move {
+            uncancellable(async move {
+                let filter = doc! { "userId": to_bson(&user_id)? };
+                Ok(self.coll.find(filter).await?.try_collect().await?)
+            })
+            .await
+        })
+    }
+
+    fn insert(&'static self, engine: ExternalEngine) -> BoxFuture<'static, Result<(), RepoError>> {
+        Box::pin(async move {
+            uncancellable(async move { self.coll.insert_one(engine).await })
+                .await
+                .map(drop)
+                .map_err(RepoError::from)
+        })
+    }
+
+    fn update(&'static self, engine: ExternalEngine) -> BoxFuture<'static, Result<(), RepoError>> {
+        Box::pin(async move {
+            uncancellable(async move {
+                // Set individual fields, to keep any that are unknown here.
+                let mut fields = to_document(&engine)?;
+                fields.remove("_id");
+                self.coll
+                    .update_one(doc! { "_id": engine.id.0 }, doc! { "$set": fields })
+                    .await?;
+                Ok(())
+            })
+            .await
+        })
+    }
+
+    fn delete(&'static self, id: EngineId) -> BoxFuture<'static, Result<(), RepoError>> {
+        Box::pin(async move {
+            uncancellable(async move { self.coll.delete_one(doc! { "_id": id.0 }).await })
+                .await
+                .map(drop)
+                .map_err(RepoError::from)
+        })
+    }
+
+    fn ping(&'static self) -> BoxFuture<'static, Result<(), RepoError>> {
+        Box::pin(async move {
+            uncancellable(async move { self.db.run_command(doc! { "ping": 1 }).await })
+                .await
+                .map(drop)
+                .map_err(RepoError::from)
+        })
+    }
+
+    fn watch(&'static self, cache: &'static Cache) -> BoxFuture<'static, Result<(), RepoError>> {
+        Box::pin(async move {
+            // Requires a replica set or sharded cluster.
+            let mut changes = self.coll.watch().await?;
+            // Changes before the stream was opened may have been missed.
+            cache.clear();
+            while let Some(change) = changes.try_next().await? {
+                match change
+                    .document_key
+                    .as_ref()
+                    .and_then(|key| key.get_str("_id").ok())
+                {
+                    Some(id) => cache.invalidate(&EngineId(id.to_owned())),
+                    None => cache.clear(),
+                }
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::Response,
+};
+use axum_extra::routing::TypedPath;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::mpsc,
+    task,
+    time::{interval, sleep},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    api::{AcquireRequest, AcquireResponse, ProviderSocketIn, ProviderSocketOut, SocketAuth, Work},
+    emit::Event,
+    job::{enqueue, record_worker, Job, Running, Submission},
+    limit::ClientIp,
+    metrics::AcquireOutcome,
+    model::{Engine, EngineId, ProviderSelector, WorkerId},
+    uci::UciOut,
+    AppState, Error,
+};
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/{id}/socket")]
+pub struct AnalyseSocketPath {
+    id: EngineId,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+pub async fn analyse_socket(
+    AnalyseSocketPath { id }: AnalyseSocketPath,
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| async move {
+        if let Err(err) = serve_analyse(socket, id, ip, state).await {
+            log::info!("analyse socket closed: {err}");
+        }
+    })
+}
+
+/// Output for the n-th work sent over a socket, counting from 1, or 0 if the
+/// connection itself is rejected.
+#[derive(Serialize, Debug)]
+struct Frame {
+    work: u64,
+    #[serde(flatten)]
+    event: Event,
+}
+
+async fn serve_analyse(
+    mut socket: WebSocket,
+    id: EngineId,
+    ip: Option<IpAddr>,
+    state: AppState,
+) -> Result<(), Error> {
+    let (engine, provider_selector) = match authenticate(&mut socket, id, ip, &state).await {
+        Ok(Some(authenticated)) => authenticated,
+        Ok(None) => return Ok(()),
+        Err(err) => {
+            let frame = Frame {
+                work: 0,
+                event: err.to_event(),
+            };
+            if send_frame(&mut socket, &frame).await.is_ok() {
+                close(&mut socket, "rejected").await;
+            }
+            return Err(err);
+        }
+    };
+
+    let (frames_tx, mut frames_rx) = mpsc::channel(1);
+    let mut seq = 0;
+    let mut current: Option<Arc<CancellationToken>> = None;
+
+    let res = loop {
+        select! {
+            msg = socket.recv() => {
+                let text = match msg {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => break Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(err)) => break Err(err.into()),
+                };
+                seq += 1;
+                match state
+                    .limits
+                    .check_engine(&engine)
+                    .and_then(|()| Ok(serde_json::from_str::<Work>(&text)?))
+                    .and_then(|work| Ok(work.sanitize(&engine)?))
+                    .and_then(|(work, pos)| {
+                        enqueue(&state, provider_selector.clone(), engine.clone(), work, pos)
+                    })
+                {
+                    Ok((cancel, events)) => {
+                        task::spawn(forward(seq, events, frames_tx.clone()));
+                        if let Some(superseded) = current.replace(cancel) {
+                            superseded.cancel();
+                        }
+                    }
+                    Err(err) => {
+                        let frame = Frame {
+                            work: seq,
+                            event: err.to_event(),
+                        };
+                        if let Err(err) = send_frame(&mut socket, &frame).await {
+                            break Err(err);
+                        }
+                    }
+                }
+            }
+            Some(frame) = frames_rx.recv() => {
+                if let Err(err) = send_frame(&mut socket, &frame).await {
+                    break Err(err);
+                }
+            }
+        }
+    };
+
+    if let Some(cancel) = current {
+        cancel.cancel();
+    }
+    res
+}
+
+/// Reads the first message with the client secret. `None` if the requester
+/// went away first.
+async fn authenticate(
+    socket: &mut WebSocket,
+    id: EngineId,
+    ip: Option<IpAddr>,
+    state: &AppState,
+) -> Result<Option<(Engine, ProviderSelector)>, Error> {
+    state.limits.check_ip(ip)?;
+    let auth: SocketAuth = match socket.recv().await {
+        Some(Ok(Message::Text(text))) => serde_json::from_str(&text)?,
+        Some(Err(err)) => return Err(err.into()),
+        Some(Ok(_)) | None => return Ok(None),
+    };
+    Ok(Some(
+        state
+            .repo
+            .find(id, auth.client_secret)
+            .await?
+            .ok_or(Error::EngineNotFound)?
+            .into_engine_and_selector(),
+    ))
+}
+
+/// Closes the connection with a reason, as a courtesy to the other side.
+async fn close(socket: &mut WebSocket, reason: &'static str) {
+    let frame = CloseFrame {
+        code: close_code::POLICY,
+        reason: reason.into(),
+    };
+    let _: Result<(), _> = socket.send(Message::Close(Some(frame))).await;
+}
+
+async fn send_frame(socket: &mut WebSocket, frame: &Frame) -> Result<(), Error> {
+    Ok(socket
+        .send(Message::text(serde_json::to_string(frame)?))
+        .await?)
+}
+
+async fn forward(work: u64, mut events: mpsc::Receiver<Event>, frames: mpsc::Sender<Frame>) {
+    while let Some(event) = events.recv().await {
+        if frames.send(Frame { work, event }).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/work/socket")]
+pub struct ProviderSocketPath;
+
+#[axum_macros::debug_handler(state = AppState)]
+pub async fn provider_socket(
+    _: ProviderSocketPath,
+    State(state): State<AppState>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| async move {
+        if let Err(err) = serve_provider(socket, state).await {
+            log::info!("provider socket closed: {err}");
+        }
+    })
+}
+
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pushes one job at a time to the provider, and reads its engine output
+/// until `bestmove`. Providers open a connection per engine process.
+/// Closes after the current job when the server is shutting down.
+async fn serve_provider(mut socket: WebSocket, state: AppState) -> Result<(), Error> {
+    let req: AcquireRequest = match socket.recv().await {
+        Some(Ok(Message::Text(text))) => serde_json::from_str(&text)?,
+        Some(Err(err)) => return Err(err.into()),
+        Some(Ok(_)) | None => return Ok(()),
+    };
+    if req.max_jobs.is_some() {
+        close(
+            &mut socket,
+            "maxJobs is not supported, open a connection per engine",
+        )
+        .await;
+        return Ok(());
+    }
+    let selector = req.provider_secret.selector();
+
+    let mut heartbeat = interval(PING_INTERVAL);
+    let mut last_seen = Instant::now();
+
+    loop {
+        let acquire = acquire_limited(&state, &selector, req.worker_id.as_ref());
+        tokio::pin!(acquire);
+        let mut job = loop {
+            select! {
+                biased;
+                _ = state.shutdown.cancelled() => {
+                    state.metrics.record_acquire(AcquireOutcome::ShuttingDown);
+                    return Ok(());
+                }
+                job = &mut acquire => break job,
+                msg = socket.recv() => {
+                    last_seen = Instant::now();
+                    match msg {
+                        Some(Ok(Message::Close(_))) | None => return Ok(()),
+                        Some(Ok(_)) => (),
+                        Some(Err(err)) => return Err(err.into()),
+                    }
+                }
+                _ = heartbeat.tick() => ping(&mut socket, last_seen).await?,
+            }
+        };
+
+        state.metrics.record_acquire(AcquireOutcome::Job);
+        state.metrics.record_acquired_job();
+        record_worker(state.sessions, &job, req.worker_id.clone());
+        let id = job.id.clone();
+        let events = job.start();
+        state
+            .running
+            .add(id.clone(), Running::new(selector.clone(), &job, &events));
+        let out = ProviderSocketOut::Work(AcquireResponse {
+            id: id.clone(),
+            engine: job.engine.clone(),
+            work: job.work.clone(),
+        });
+        let Ok(mut submission) = Submission::new(job) else {
+            continue;
+        };
+        send_provider(&mut socket, &out).await?;
+
+        let mut cancelled = false;
+        loop {
+            select! {
+                msg = socket.recv() => {
+                    last_seen = Instant::now();
+                    let text = match msg {
+                        Some(Ok(Message::Text(text))) => text,
+                        Some(Ok(Message::Close(_))) | None => return Ok(()),
+                        Some(Ok(_)) => continue,
+                        Some(Err(err)) => return Err(err.into()),
+                    };
+                    let ProviderSocketIn::Uci { id: line_id, line } = serde_json::from_str(&text)?;
+                    if line_id != id {
+                        continue;
+                    }
+                    if cancelled {
+                        if matches!(UciOut::from_line(&line)?, Some(UciOut::Bestmove { .. })) {
+                            break;
+                        }
+                    } else if submission.feed(&line).await?.is_break() {
+                        break;
+                    }
+                }
+                _ = submission.cancelled(), if !cancelled => {
+                    cancelled = true;
+                    let out = ProviderSocketOut::Cancel { id: id.clone() };
+                    send_provider(&mut socket, &out).await?;
+                }
+                _ = heartbeat.tick() => ping(&mut socket, last_seen).await?,
+            }
+        }
+    }
+}
+
+/// Acquires a job for the provider. Each job counts against the rate limit
+/// of the provider, like an acquire request.
+async fn acquire_limited(
+    state: &AppState,
+    selector: &ProviderSelector,
+    worker: Option<&WorkerId>,
+) -> Job {
+    while let Err(Error::RateLimited(retry_after)) = state.limits.check_provider(selector) {
+        sleep(retry_after).await;
+    }
+    state.hub.acquire(selector.clone(), worker).await
+}
+
+async fn send_provider(socket: &mut WebSocket, out: &ProviderSocketOut) -> Result<(), Error> {
+    Ok(socket
+        .send(Message::text(serde_json::to_string(out)?))
+        .await?)
+}
+
+async fn ping(socket: &mut WebSocket, last_seen: Instant) -> Result<(), Error> {
+    if last_seen.elapsed() > PROVIDER_TIMEOUT {
+        return Err(Error::ProviderTimeout);
+    }
+    Ok(socket.send(Message::Ping(Default::default())).await?)
+}
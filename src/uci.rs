@@ -17,6 +17,8 @@ pub enum ProtocolError {
     UnexpectedEndOfLine,
     #[error("invalid move: {0}")]
     InvalidMove(#[from] ParseUciMoveError),
+    #[error("illegal move: {0}")]
+    IllegalMove(UciMove),
     #[error("invalid integer: {0}")]
     InvalidInteger(#[from] ParseIntError),
     #[error("invalid multipv: {0}")]